@@ -1,33 +1,319 @@
+use std::collections::HashMap;
+use std::error::Error;
 use std::fs::File;
 use std::io::BufReader;
-use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use cpal::traits::{DeviceTrait, HostTrait};
+use log::{info, warn};
 use rodio::{OutputStream, OutputStreamBuilder, Sink, Source};
 
+// Minimum delay between two searches of the lost output device
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A sound playing on the output and virtual devices.
+pub type Voice = (Box<dyn Playback>, Box<dyn Playback>);
+/// Sounds playing by pad, the last one being the most recent.
+pub type Sinks = HashMap<u8, Vec<Voice>>;
+/// A sound that started, with its duration if it is known.
+pub type Played = (Box<dyn Playback>, Option<Duration>);
+
+/// Audio device playing the sounds, a rodio stream in production.
+pub trait AudioOutput {
+  /// Open the device by name, None if it isn't found.
+  fn open(name: &str) -> Option<Self> where Self: Sized;
+  fn is_available(&self) -> bool;
+  /// Check if the device is still there, returns true if its availability changed.
+  fn watch(&mut self) -> bool;
+  fn play_sound(&self, path: &str, volume: f32) -> Result<Played, Box<dyn Error>>;
+}
+
+/// A sound being played, a rodio sink in production.
+pub trait Playback {
+  fn play(&self);
+  fn pause(&self);
+  fn stop(&self);
+  fn is_paused(&self) -> bool;
+  /// Volume multiplier of the sound, 1 being its own volume.
+  fn set_volume(&self, volume: f32);
+  /// True once the sound ended or has been stopped.
+  fn empty(&self) -> bool;
+  /// Position in the sound playing, from the start of the sound.
+  fn position(&self) -> Duration;
+  /// Play another sound right after the queued ones, returns its duration if it is known.
+  fn append(&self, path: &str, volume: f32) -> Result<Option<Duration>, Box<dyn Error>>;
+  /// Number of sounds left to play, the one playing included.
+  fn queued(&self) -> usize;
+}
+
+impl Playback for Sink {
+  fn play(&self) {
+    Sink::play(self)
+  }
+
+  fn pause(&self) {
+    Sink::pause(self)
+  }
+
+  fn stop(&self) {
+    Sink::stop(self)
+  }
+
+  fn is_paused(&self) -> bool {
+    Sink::is_paused(self)
+  }
+
+  fn set_volume(&self, volume: f32) {
+    Sink::set_volume(self, volume)
+  }
+
+  fn empty(&self) -> bool {
+    Sink::empty(self)
+  }
+
+  fn position(&self) -> Duration {
+    self.get_pos()
+  }
+
+  fn append(&self, path: &str, volume: f32) -> Result<Option<Duration>, Box<dyn Error>> {
+    let source = decode(path, volume)?;
+    let duration = source.total_duration();
+    Sink::append(self, source);
+    Ok(duration)
+  }
+
+  fn queued(&self) -> usize {
+    self.len()
+  }
+}
+
+/// Sink playing on the stream of a bus. rodio keeps the sounds of a dropped stream queued forever,
+/// they are ended here instead.
+struct BusSink {
+  sink: Sink,
+  // Set once the stream is dropped
+  lost: Arc<AtomicBool>,
+}
+
+impl BusSink {
+  fn is_lost(&self) -> bool {
+    self.lost.load(Ordering::Relaxed)
+  }
+}
+
+impl Playback for BusSink {
+  fn play(&self) {
+    self.sink.play()
+  }
+
+  fn pause(&self) {
+    self.sink.pause()
+  }
+
+  fn stop(&self) {
+    self.sink.stop()
+  }
+
+  fn is_paused(&self) -> bool {
+    self.sink.is_paused()
+  }
+
+  fn set_volume(&self, volume: f32) {
+    self.sink.set_volume(volume)
+  }
+
+  fn empty(&self) -> bool {
+    self.is_lost() || self.sink.empty()
+  }
+
+  fn position(&self) -> Duration {
+    self.sink.get_pos()
+  }
+
+  fn append(&self, path: &str, volume: f32) -> Result<Option<Duration>, Box<dyn Error>> {
+    Playback::append(&self.sink, path, volume)
+  }
+
+  fn queued(&self) -> usize {
+    if self.is_lost() { 0 } else { self.sink.len() }
+  }
+}
+
 pub fn get_output_devices() -> Vec<String> {
   let host = cpal::default_host();
-  host.devices().unwrap().map(|device| device.name().unwrap()).collect()
+  match host.devices() {
+    Ok(devices) => devices.filter_map(|device| device.name().ok()).collect(),
+    Err(_) => vec![],
+  }
 }
 
-pub fn select_output_device(name: &str) -> Option<OutputStream> {
+/// Open the stream of the device, `errored` is set on any error of the stream.
+pub fn select_output_device(name: &str, errored: Arc<AtomicBool>) -> Option<OutputStream> {
   let host = cpal::default_host();
-  let output_device = host.devices().unwrap().find(|device| device.name().unwrap().eq(name));
-  if let Some(device) = output_device {
-    OutputStreamBuilder::from_device(device).unwrap().open_stream().ok()
-  } else {
-    None
+  let output_device = host.devices().ok()?.find(|device| device.name().is_ok_and(|device_name| device_name.eq(name)))?;
+  let stream_name = name.to_string();
+  OutputStreamBuilder::from_device(output_device).ok()?
+    .with_error_callback(move |error| {
+      warn!("Audio stream error on {}: {}", stream_name, error);
+      // Some backends report a lost device as any other error, ALSA among them
+      errored.store(true, Ordering::Relaxed);
+    })
+    .open_stream()
+    .ok()
+}
+
+/// Volume changing over time, applied to the sounds of a pad.
+/// The sounds fading to silence are stopped once the fade is over.
+#[derive(Debug, Clone, Copy)]
+pub struct Fade {
+  start: Instant,
+  length: Duration,
+  from: f32,
+  to: f32,
+}
+
+impl Fade {
+  pub fn new(start: Instant, length: Duration, from: f32, to: f32) -> Self {
+    Fade { start, length, from, to }
+  }
+
+  /// Volume multiplier at the given time.
+  pub fn volume(&self, now: Instant) -> f32 {
+    if self.length.is_zero() {
+      return self.to;
+    }
+    let ratio = now.saturating_duration_since(self.start).as_secs_f32() / self.length.as_secs_f32();
+    self.from + (self.to - self.from) * ratio.clamp(0.0, 1.0)
+  }
+
+  pub fn is_silent_at_end(&self) -> bool {
+    self.to == 0.0
+  }
+
+  pub fn is_over(&self, now: Instant) -> bool {
+    now.saturating_duration_since(self.start) >= self.length
+  }
+}
+
+/// Output device that is reopened by name when it disappears and comes back.
+pub struct Bus {
+  name: String,
+  stream: Option<OutputStream>,
+  // Set by the stream on any error, the device may be gone
+  errored: Arc<AtomicBool>,
+  // Shared with the sinks of the stream, set once it is dropped
+  lost: Arc<AtomicBool>,
+  last_check: Instant,
+  // Search of the device running on another thread, true if it is found
+  probe: Option<Receiver<bool>>,
+}
+
+impl Bus {
+  /// Search the device on another thread at most once per interval, listing the devices probes each of them
+  /// and is too slow for the main loop. Returns whether it is found once a search is over.
+  fn search(&mut self) -> Option<bool> {
+    match self.probe.as_ref().map(Receiver::try_recv) {
+      Some(Ok(found)) => {
+        self.probe = None;
+        Some(found)
+      }
+      Some(Err(TryRecvError::Disconnected)) => {
+        self.probe = None;
+        None
+      }
+      Some(Err(TryRecvError::Empty)) => None,
+      None => {
+        if self.last_check.elapsed() >= WATCHDOG_INTERVAL {
+          self.last_check = Instant::now();
+          let (tx_probe, rx_probe) = mpsc::channel();
+          let name = self.name.clone();
+          thread::spawn(move || tx_probe.send(get_output_devices().contains(&name)));
+          self.probe = Some(rx_probe);
+        }
+        None
+      }
+    }
   }
 }
 
-pub fn play_sound(handle: &OutputStream, path: &str, volume: f32) -> (Sink, Option<Duration>) {
-  let reader = BufReader::new(File::open(path).unwrap());
-  let source = rodio::Decoder::new(reader).unwrap()
-    .amplify(volume);
+impl AudioOutput for Bus {
+  fn open(name: &str) -> Option<Bus> {
+    let errored = Arc::new(AtomicBool::new(false));
+    let stream = select_output_device(name, errored.clone())?;
+    Some(Bus {
+      name: name.to_string(),
+      stream: Some(stream),
+      errored,
+      lost: Arc::new(AtomicBool::new(false)),
+      last_check: Instant::now(),
+      probe: None,
+    })
+  }
+
+  fn is_available(&self) -> bool {
+    self.stream.is_some()
+  }
+
+  /// Drop the stream once the device is no longer found after a stream error, and reopen it when it is back.
+  /// Returns true if the availability changed.
+  fn watch(&mut self) -> bool {
+    if self.stream.is_some() {
+      if !self.errored.load(Ordering::Relaxed) {
+        return false;
+      }
+      match self.search() {
+        // The device is still there, the error didn't last
+        Some(true) => self.errored.store(false, Ordering::Relaxed),
+        Some(false) => {
+          warn!("Audio device {} is unavailable", self.name);
+          self.lost.store(true, Ordering::Relaxed);
+          self.stream = None;
+          return true;
+        }
+        None => {}
+      }
+      return false;
+    }
+    if self.search() == Some(true) {
+      self.errored.store(false, Ordering::Relaxed);
+      self.stream = select_output_device(&self.name, self.errored.clone());
+      if self.stream.is_some() {
+        self.lost = Arc::new(AtomicBool::new(false));
+        info!("Audio device {} is back", self.name);
+        return true;
+      }
+    }
+    false
+  }
+
+  /// Play the sound on the device, an empty sink is returned if the device is unavailable.
+  fn play_sound(&self, path: &str, volume: f32) -> Result<Played, Box<dyn Error>> {
+    match &self.stream {
+      Some(stream) => {
+        let (sink, duration) = play_sound(stream, path, volume)?;
+        Ok((Box::new(BusSink { sink, lost: self.lost.clone() }), duration))
+      }
+      _ => Ok((Box::new(Sink::new().0), None)),
+    }
+  }
+}
+
+pub fn play_sound(handle: &OutputStream, path: &str, volume: f32) -> Result<(Sink, Option<Duration>), Box<dyn Error>> {
+  let source = decode(path, volume)?;
 
   let sink = Sink::connect_new(handle.mixer());
   let duration = source.total_duration();
   sink.append(source);
 
-  (sink, duration)
-}
\ No newline at end of file
+  Ok((sink, duration))
+}
+
+fn decode(path: &str, volume: f32) -> Result<impl Source + Send + 'static, Box<dyn Error>> {
+  let reader = BufReader::new(File::open(path)?);
+  Ok(rodio::Decoder::new(reader)?.amplify(volume))
+}
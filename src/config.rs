@@ -1,3 +1,5 @@
+use std::str::FromStr;
+
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug)]
@@ -6,6 +8,8 @@ pub struct Config {
     midi_out_device: Option<String>,
     output_device: Option<String>,
     virtual_device: Option<String>,
+    // Mapping file used instead of the launchpad profiles
+    controller_profile: Option<String>,
     bookmark_1: Option<String>,
     bookmark_2: Option<String>,
     bookmark_3: Option<String>,
@@ -13,14 +17,137 @@ pub struct Config {
     bookmark_5: Option<String>,
     bookmark_6: Option<String>,
     bookmark_7: Option<String>,
+    // Bookmarks after the 7th, for launchpads with more bookmark buttons
+    #[serde(default)]
+    extra_bookmarks: Vec<Option<String>>,
     hold_to: HoldMode,
+    #[serde(default)]
+    playback_progress: bool,
+    #[serde(default)]
+    progress_strip: bool,
+    #[serde(default = "default_scroll_text")]
+    scroll_text: bool,
+    mirror: Option<MirrorConfig>,
+    quantize: Option<QuantizeConfig>,
+    crossfade: Option<CrossfadeConfig>,
+}
+
+/// Fade the music pads into each other when one starts while another is playing.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CrossfadeConfig {
+    // In milliseconds, the pads can have their own
+    #[serde(default = "default_crossfade_length")]
+    pub length: u64,
+    // Numbers of the bookmarks, from 1, whose pads are all music pads
+    #[serde(default)]
+    pub bookmarks: Vec<usize>,
+}
+
+fn default_crossfade_length() -> u64 {
+    3000
+}
+
+/// Launch the pads that opt in on the next beat or bar instead of right away.
+#[derive(Deserialize, Debug, Clone)]
+pub struct QuantizeConfig {
+    // Internal tempo in beats per minute, used when there's no clock device
+    #[serde(default = "default_tempo")]
+    pub tempo: f32,
+    #[serde(default)]
+    pub division: Division,
+    #[serde(default = "default_beats_per_bar")]
+    pub beats_per_bar: u32,
+    // Midi input sending the clock to follow
+    pub clock_device: Option<String>,
+    // Numbers of the bookmarks, from 1, whose pads are all quantized
+    #[serde(default)]
+    pub bookmarks: Vec<usize>,
+}
+
+impl Default for QuantizeConfig {
+    /// Followed by the notes that opt in when the config has no quantize block.
+    fn default() -> Self {
+        QuantizeConfig {
+            tempo: default_tempo(),
+            division: Division::default(),
+            beats_per_bar: default_beats_per_bar(),
+            clock_device: None,
+            bookmarks: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum Division {
+    #[default]
+    Beat,
+    Bar,
+}
+
+fn default_tempo() -> f32 {
+    120.0
+}
+
+fn default_beats_per_bar() -> u32 {
+    4
+}
+
+/// Second midi output notified when a pad plays, stops or when the page changes.
+#[derive(Deserialize, Debug, Clone)]
+pub struct MirrorConfig {
+    pub device: String,
+    // Midi channel, from 0 to 15
+    #[serde(default)]
+    pub channel: u8,
+    #[serde(default = "default_pad_message")]
+    pub pad_played: MirrorMessage,
+    #[serde(default = "default_pad_message")]
+    pub pad_stopped: MirrorMessage,
+    #[serde(default = "default_page_message")]
+    pub page_changed: MirrorMessage,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum MirrorMessage {
+    Note,
+    ControlChange,
+    ProgramChange,
+    Nothing,
+}
+
+fn default_pad_message() -> MirrorMessage {
+    MirrorMessage::Note
+}
+
+fn default_page_message() -> MirrorMessage {
+    MirrorMessage::ProgramChange
+}
+
+fn default_scroll_text() -> bool {
+    true
 }
 
-#[derive(Deserialize, Debug, PartialEq)]
-pub(crate) enum HoldMode {
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum HoldMode {
     Normal,
     Pause,
-    Stop
+    Stop,
+    // One press starts the sound, the next one stops it
+    Toggle,
+}
+
+impl FromStr for HoldMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.trim() {
+            "Normal" => Ok(HoldMode::Normal),
+            "Pause" => Ok(HoldMode::Pause),
+            "Stop" => Ok(HoldMode::Stop),
+            "Toggle" => Ok(HoldMode::Toggle),
+            _ => Err(format!("Unknown hold mode {}", mode)),
+        }
+    }
 }
 
 impl Config {
@@ -42,6 +169,10 @@ impl Config {
         self.virtual_device.clone()
     }
 
+    pub fn get_controller_profile(&self) -> Option<&str> {
+        self.controller_profile.as_deref()
+    }
+
     pub fn get_bookmark(&self, index: usize) -> Option<String> {
         match index {
             0 => self.bookmark_1.clone(),
@@ -51,7 +182,7 @@ impl Config {
             4 => self.bookmark_5.clone(),
             5 => self.bookmark_6.clone(),
             6 => self.bookmark_7.clone(),
-            _ => None
+            _ => self.extra_bookmarks.get(index - 7).cloned().flatten()
         }
     }
 
@@ -64,7 +195,7 @@ impl Config {
             4 => self.bookmark_5.is_some(),
             5 => self.bookmark_6.is_some(),
             6 => self.bookmark_7.is_some(),
-            _ => false
+            _ => self.extra_bookmarks.get(index - 7).is_some_and(|bookmark| bookmark.is_some())
         }
     }
 
@@ -72,11 +203,40 @@ impl Config {
         &self.hold_to
     }
 
+    pub fn show_playback_progress(&self) -> bool {
+        self.playback_progress
+    }
+
+    pub fn show_progress_strip(&self) -> bool {
+        self.progress_strip
+    }
+
+    pub fn scroll_text_enabled(&self) -> bool {
+        self.scroll_text
+    }
+
+    pub fn get_mirror(&self) -> Option<&MirrorConfig> {
+        self.mirror.as_ref()
+    }
+
+    pub fn get_quantize(&self) -> Option<&QuantizeConfig> {
+        self.quantize.as_ref()
+    }
+
+    pub fn get_crossfade(&self) -> Option<&CrossfadeConfig> {
+        self.crossfade.as_ref()
+    }
+
+    pub fn set_hold_to_mode(&mut self, mode: HoldMode) {
+        self.hold_to = mode;
+    }
+
     pub fn swap_hold_to_play(&mut self) {
         self.hold_to = match self.hold_to {
             HoldMode::Normal => HoldMode::Stop,
             HoldMode::Stop => HoldMode::Pause,
-            HoldMode::Pause => HoldMode::Normal,
+            HoldMode::Pause => HoldMode::Toggle,
+            HoldMode::Toggle => HoldMode::Normal,
         }
     }
 }
\ No newline at end of file
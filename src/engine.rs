@@ -0,0 +1,719 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use log::error;
+
+use crate::NoteEvent;
+use crate::audio::{AudioOutput, Fade, Sinks, Voice};
+use crate::config::{Config, HoldMode};
+use crate::launchpad::Launchpad;
+use crate::led::LedCommand;
+use crate::midi;
+use crate::mirror::Mirror;
+use crate::pad::{Pad, PadEvent, PadState, Retrigger};
+use crate::playlist::{Playlist, Queue};
+use crate::progress::{self, Progress};
+use crate::quantize::{MidiClock, Quantizer};
+use crate::referential::{Note, Referential};
+use crate::sequence::{self, Sequence, Step};
+
+const VOICE_VOLUME: f32 = 1.0;
+const LOOPBACK_VOLUME: f32 = 0.10;
+const PROGRESS_REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+// Delay between two volume changes of a fade
+const FADE_STEP: Duration = Duration::from_millis(20);
+// Longest delay between the two presses of a double press
+const DOUBLE_PRESS: Duration = Duration::from_millis(400);
+
+/// The soundboard itself: turns the pad events into sounds and LED commands.
+/// It doesn't know about the midi ports, the caller feeds it the events and forwards the LED commands.
+pub struct Engine {
+  config: Config,
+  launchpad: Arc<Launchpad>,
+  referential: Referential,
+  output_bus: Box<dyn AudioOutput>,
+  virtual_bus: Box<dyn AudioOutput>,
+  tx_midi: Sender<LedCommand>,
+  sinks: Sinks,
+  // Pads that aren't idle
+  pads: HashMap<u8, Pad>,
+  // Pads fading out before stopping
+  fades: HashMap<u8, Fade>,
+  // Macros being run by the pads
+  sequences: HashMap<u8, Sequence>,
+  // Where the playing playlists are
+  playlists: HashMap<u8, Queue>,
+  progress: Progress,
+  mirror: Mirror,
+  quantizer: Quantizer,
+  page_overlay: bool,
+  last_page: (u8, usize),
+  last_tick: Instant,
+}
+
+impl Engine {
+  pub fn new(config: Config, launchpad: Arc<Launchpad>, referential: Referential, output_bus: Box<dyn AudioOutput>, virtual_bus: Box<dyn AudioOutput>, tx_midi: Sender<LedCommand>, midi_clock: Option<Arc<MidiClock>>) -> Self {
+    let mirror = Mirror::open(config.get_mirror());
+    let quantizer = Quantizer::new(config.get_quantize(), midi_clock);
+    let last_page = (referential.get_current_bookmark(), referential.get_current_page_index());
+    Engine {
+      config,
+      launchpad,
+      referential,
+      output_bus,
+      virtual_bus,
+      tx_midi,
+      sinks: Sinks::new(),
+      pads: HashMap::new(),
+      fades: HashMap::new(),
+      sequences: HashMap::new(),
+      playlists: HashMap::new(),
+      progress: Progress::new(),
+      mirror,
+      quantizer,
+      page_overlay: false,
+      last_page,
+      last_tick: Instant::now(),
+    }
+  }
+
+  /// Light the launchpad for the first page.
+  pub fn start(&mut self) {
+    midi::refresh_grid(&self.launchpad, &self.config, &mut self.referential, &self.tx_midi, true);
+  }
+
+  /// Launch the quantized pads that are due, run the due steps of the macros, apply the single presses
+  /// on the playlists and step the fades, returns how long to wait for the next event.
+  pub fn update(&mut self, now: Instant) -> Duration {
+    for note in self.quantizer.due(now) {
+      self.transition(note.note_id, PadEvent::Launched);
+    }
+    self.step_sequences(now);
+    let single_presses: Vec<u8> = self.playlists.iter_mut()
+      .filter_map(|(note_id, queue)| queue.single_press_over(now, DOUBLE_PRESS).then_some(*note_id))
+      .collect();
+    for note_id in single_presses {
+      self.transition(note_id, PadEvent::Press);
+    }
+    self.step_fades(now);
+
+    let timeout = self.quantizer.time_to_next_launch(now)
+      .into_iter()
+      .chain(self.sequences.values().filter_map(|sequence| sequence.time_to_next_step(now)))
+      .chain(self.playlists.values().filter_map(|queue| queue.single_press_due(DOUBLE_PRESS)).map(|due| due.saturating_duration_since(now)))
+      .fold(PROGRESS_REFRESH_INTERVAL, Duration::min);
+    if self.fades.is_empty() { timeout } else { timeout.min(FADE_STEP) }
+  }
+
+  /// Periodic work while no event comes: audio devices, ended sounds and playback progress.
+  pub fn tick(&mut self, now: Instant) {
+    if now.saturating_duration_since(self.last_tick) < PROGRESS_REFRESH_INTERVAL {
+      return;
+    }
+    self.last_tick = now;
+    // Show the unavailable audio devices until they come back
+    if self.output_bus.watch() || !self.output_bus.is_available() {
+      midi::show_bus_status(self.launchpad.output_status_note(), self.output_bus.is_available(), &self.launchpad, &self.tx_midi);
+    }
+    if self.virtual_bus.watch() || !self.virtual_bus.is_available() {
+      midi::show_bus_status(self.launchpad.virtual_status_note(), self.virtual_bus.is_available(), &self.launchpad, &self.tx_midi);
+    }
+    self.advance_playlists();
+    // Forget the sounds that ended by themselves, and the stopped ones
+    for voices in self.sinks.values_mut() {
+      voices.retain(|(audio_sink, virtual_sink)| !(audio_sink.empty() && virtual_sink.empty()));
+    }
+    let ended: Vec<u8> = self.pads.iter()
+      .filter(|(note_id, pad)| match pad.state {
+        // A macro waiting for its next step has nothing to play
+        PadState::Playing => self.sinks.get(note_id).is_none_or(|voices| voices.is_empty()) && !self.sequences.contains_key(note_id),
+        PadState::Stopping => !self.fades.contains_key(note_id),
+        _ => false,
+      })
+      .map(|(note_id, _)| *note_id)
+      .collect();
+    for note_id in ended {
+      self.transition(note_id, PadEvent::Ended);
+    }
+    if !self.page_overlay {
+      self.progress.refresh(&self.launchpad, &self.config, &self.referential, &self.sinks, &self.tx_midi);
+    }
+  }
+
+  /// Put the launchpad back in programmer mode and light it again, after it has been plugged back.
+  pub fn device_reconnected(&mut self) {
+    self.tx_midi.send(LedCommand::Reconnect).unwrap();
+    if !self.launchpad.programmer_mode_command().is_empty() {
+      self.tx_midi.send(LedCommand::SysEx(self.launchpad.programmer_mode_command().to_vec())).unwrap();
+    }
+    self.page_overlay = false;
+    self.refresh_page(true);
+  }
+
+  /// Handle a pad or button event, returns false once the session is ended.
+  pub fn handle(&mut self, event: NoteEvent) -> bool {
+    let running = self.handle_event(event);
+    self.notify_page_change();
+    running
+  }
+
+  /// Stop the sounds and give the launchpad back in the state it was found.
+  pub fn end_session(&mut self) {
+    midi::actions::end_session(&self.launchpad, &self.tx_midi, &mut self.sinks);
+  }
+
+  fn handle_event(&mut self, (note_id, is_on): NoteEvent) -> bool {
+    let launchpad = self.launchpad.clone();
+    let tx_midi = self.tx_midi.clone();
+    if !is_on && self.page_overlay && (note_id == launchpad.prev_page_note() || note_id == launchpad.next_page_note()) {
+      self.page_overlay = false;
+
+      self.refresh_page(false);
+      midi::scroll_text(&launchpad, &self.config, &tx_midi, self.referential.current_page().get_name());
+      return true;
+    }
+    if !is_on {
+      self.transition(note_id, PadEvent::Release);
+      return true;
+    }
+
+    if note_id == launchpad.end_session_note() {
+      return false;
+    }
+    if note_id == launchpad.stop_note() {
+      for note_id in self.busy_pads() {
+        self.transition(note_id, PadEvent::StopAll);
+      }
+      return true;
+    }
+    if note_id == launchpad.first_page_note() {
+      self.referential.first_page();
+
+      self.refresh_page(false);
+      midi::scroll_text(&launchpad, &self.config, &tx_midi, self.referential.current_page().get_name());
+      return true;
+    }
+    if note_id == launchpad.last_page_note() {
+      self.referential.last_page();
+
+      self.refresh_page(false);
+      midi::scroll_text(&launchpad, &self.config, &tx_midi, self.referential.current_page().get_name());
+      return true;
+    }
+    // The page overlay stays on the grid until the navigation button is released
+    if note_id == launchpad.prev_page_note() {
+      self.referential.previous_page();
+
+      self.page_overlay = true;
+      midi::show_page_overlay(&launchpad, &self.referential, &tx_midi);
+      return true;
+    }
+    if note_id == launchpad.next_page_note() {
+      self.referential.next_page();
+
+      self.page_overlay = true;
+      midi::show_page_overlay(&launchpad, &self.referential, &tx_midi);
+      return true;
+    }
+    if self.page_overlay {
+      if let Some(index) = launchpad.grid_notes().iter().position(|&r| r == note_id) {
+        self.referential.goto_page(index);
+        midi::show_page_overlay(&launchpad, &self.referential, &tx_midi);
+      }
+      return true;
+    }
+    if note_id == launchpad.swap_hold_mode_note() {
+      self.change_hold_mode(None);
+      return true;
+    }
+    if let Some(index) = launchpad.page_notes().iter().position(|&r| r == note_id) {
+      self.goto_page(index);
+      return true;
+    }
+    let bookmark_notes = launchpad.bookmark_notes();
+    if let Some(index) = bookmark_notes.iter().position(|&r| r == note_id) {
+      if !self.config.bookmark_exists(index) {
+        return true;
+      }
+      self.referential.set_current_bookmark(bookmark_notes[index]);
+      // Get the bookmark parameter from Config based on index
+      let bookmark_path = self.config.get_bookmark(index).expect("No path found for bookmark");
+      self.referential.init(bookmark_path.clone());
+
+      self.refresh_page(true);
+      midi::scroll_text(&launchpad, &self.config, &tx_midi, &bookmark_path);
+      return true;
+    }
+    // The press on a playing playlist waits to know if it is a double press
+    if let Some(double) = self.playlist_press(note_id) {
+      if double {
+        self.skip_track(note_id, true);
+      }
+      return true;
+    }
+    self.transition(note_id, PadEvent::Press);
+    true
+  }
+
+  fn busy_pads(&self) -> Vec<u8> {
+    self.pads.keys().copied().collect()
+  }
+
+  /// Number of the current bookmark as in the config, from 1.
+  fn bookmark_number(&self) -> Option<usize> {
+    let bookmark = self.referential.get_current_bookmark();
+    self.launchpad.bookmark_notes().iter().position(|&r| r == bookmark).map(|index| index + 1)
+  }
+
+  /// Tell the mirror when the page changed, from a button or from a macro.
+  fn notify_page_change(&mut self) {
+    let page = (self.referential.get_current_bookmark(), self.referential.get_current_page_index());
+    if page != self.last_page {
+      self.last_page = page;
+      self.mirror.page_changed(page.1);
+    }
+  }
+
+  fn goto_page(&mut self, index: usize) {
+    self.referential.goto_page(index);
+
+    self.refresh_page(false);
+    midi::scroll_text(&self.launchpad, &self.config, &self.tx_midi, self.referential.current_page().get_name());
+  }
+
+  /// Switch to the hold mode, or to the next one, the pads paused in the previous mode are stopped.
+  fn change_hold_mode(&mut self, mode: Option<HoldMode>) {
+    match mode {
+      Some(mode) => self.config.set_hold_to_mode(mode),
+      None => self.config.swap_hold_to_play(),
+    }
+    for note_id in self.busy_pads() {
+      self.transition(note_id, PadEvent::ModeChange);
+    }
+
+    self.refresh_page(true);
+  }
+
+  /// Apply the event to the pad, then do what its new state requires and light it accordingly.
+  fn transition(&mut self, note_id: u8, event: PadEvent) {
+    let current_note = self.referential.get_note(note_id);
+    let Some(mut pad) = self.pads.get(&note_id).copied().or_else(|| current_note.map(Pad::new)) else {
+      return;
+    };
+    // The mode of the page replaces the global one for this pad
+    let mode = pad.note.hold_mode.unwrap_or(*self.config.get_hold_to_mode());
+    // A press launches the note of the current page
+    let launched = if event == PadEvent::Press { current_note.unwrap_or(pad.note) } else { pad.note };
+    let division = self.quantizer.division(&launched, self.bookmark_number());
+    let Some(state) = pad.state.next(event, mode, pad.note.retrigger, division.is_some()) else {
+      return;
+    };
+    let from = pad.state;
+    pad.state = state;
+    let mut started = false;
+
+    match (from, state) {
+      (PadState::Paused, PadState::Playing) => {
+        for (audio_sink, virtual_sink) in self.sinks.get(&note_id).into_iter().flatten() {
+          audio_sink.play();
+          virtual_sink.play();
+        }
+        if let Some(sequence) = self.sequences.get_mut(&note_id) {
+          sequence.resume(Instant::now());
+        }
+        self.mirror.pad_played(note_id);
+      }
+      (_, PadState::Playing | PadState::Queued) => {
+        // A press launches the sound of the current page, even if the pad played another one before
+        if event == PadEvent::Press {
+          let Some(note) = current_note else {
+            return;
+          };
+          pad.note = note;
+        }
+        pad.crossfade = self.crossfade_length(&pad.note);
+        // The sound still fading out is cut
+        if from == PadState::Stopping {
+          self.fades.remove(&note_id);
+          for (audio_sink, virtual_sink) in self.sinks.remove(&note_id).into_iter().flatten() {
+            audio_sink.stop();
+            virtual_sink.stop();
+          }
+        }
+        if state == PadState::Queued {
+          // Only the notes with a division are queued
+          self.quantizer.queue(pad.note, division.unwrap_or_default());
+        } else if let Some(steps) = pad.note.steps {
+          // A macro starts over, without the sounds it played before
+          for (audio_sink, virtual_sink) in self.sinks.remove(&note_id).into_iter().flatten() {
+            audio_sink.stop();
+            virtual_sink.stop();
+          }
+          self.sequences.insert(note_id, Sequence::new(steps, Instant::now()));
+          self.mirror.pad_played(note_id);
+          started = true;
+        } else if let Some(playlist) = pad.note.playlist {
+          let queue = Queue::new(playlist, pad.note.shuffle);
+          if self.play_track(&mut pad, queue) {
+            self.mirror.pad_played(note_id);
+            started = true;
+          } else {
+            pad.state = PadState::Idle;
+          }
+        } else if self.play_sound(&mut pad) {
+          self.mirror.pad_played(note_id);
+          started = true;
+        } else {
+          pad.state = PadState::Idle;
+        }
+      }
+      (PadState::Playing, PadState::Paused) => {
+        for (audio_sink, virtual_sink) in self.sinks.get(&note_id).into_iter().flatten() {
+          audio_sink.pause();
+          virtual_sink.pause();
+        }
+        if let Some(sequence) = self.sequences.get_mut(&note_id) {
+          sequence.pause(Instant::now());
+        }
+        self.mirror.pad_stopped(note_id);
+      }
+      (_, PadState::Stopping) => {
+        self.sequences.remove(&note_id);
+        self.playlists.remove(&note_id);
+        let fade_out = match event {
+          PadEvent::Choked => pad.note.fade_out,
+          // Over the same length as the fade in, for the volume to stay even
+          PadEvent::CrossFaded(length) => Some(length),
+          _ => None,
+        };
+        match fade_out {
+          Some(length) => {
+            // From where a fade in got
+            let now = Instant::now();
+            let volume = self.fades.get(&note_id).map_or(1.0, |fade| fade.volume(now));
+            self.fades.insert(note_id, Fade::new(now, length, volume, 0.0));
+          }
+          _ => {
+            self.fades.remove(&note_id);
+            for (audio_sink, virtual_sink) in self.sinks.get(&note_id).into_iter().flatten() {
+              audio_sink.stop();
+              virtual_sink.stop();
+            }
+          }
+        }
+        // A paused pad has already been notified
+        if from == PadState::Playing {
+          self.mirror.pad_stopped(note_id);
+        }
+      }
+      (_, PadState::Idle) => {
+        if from == PadState::Queued {
+          self.quantizer.cancel(note_id);
+        }
+        if from == PadState::Playing {
+          self.mirror.pad_stopped(note_id);
+        }
+        self.sequences.remove(&note_id);
+        self.playlists.remove(&note_id);
+        self.fades.remove(&note_id);
+        for (audio_sink, virtual_sink) in self.sinks.remove(&note_id).into_iter().flatten() {
+          audio_sink.stop();
+          virtual_sink.stop();
+        }
+      }
+      _ => {}
+    }
+
+    if pad.state == PadState::Idle {
+      self.pads.remove(&note_id);
+    } else {
+      self.pads.insert(note_id, pad);
+    }
+    self.show_pad(note_id);
+
+    if started && let Some(group) = pad.note.choke {
+      self.choke(note_id, group);
+    }
+    if started && let Some(length) = pad.crossfade {
+      self.crossfade(note_id, length);
+    }
+    // The first steps of a macro run right away
+    if started && pad.note.steps.is_some() {
+      self.step_sequences(Instant::now());
+    }
+  }
+
+  /// Stop the other pads of the choke group, like the open and closed hi-hats of a drum machine.
+  fn choke(&mut self, note_id: u8, group: &str) {
+    let choked: Vec<u8> = self.pads.iter()
+      .filter(|(other_id, other)| **other_id != note_id && other.note.choke == Some(group))
+      .map(|(other_id, _)| *other_id)
+      .collect();
+    for other_id in choked {
+      self.transition(other_id, PadEvent::Choked);
+    }
+  }
+
+  /// Run the steps of the macros that are due, and show how far they are on their pads.
+  fn step_sequences(&mut self, now: Instant) {
+    let note_ids: Vec<u8> = self.sequences.keys().copied().collect();
+    for note_id in note_ids {
+      // A previous step may have stopped the macro
+      let Some(sequence) = self.sequences.get_mut(&note_id) else {
+        continue;
+      };
+      let steps = sequence.due(now);
+      if sequence.is_over() {
+        self.sequences.remove(&note_id);
+      }
+      if steps.is_empty() {
+        continue;
+      }
+      for step in steps {
+        if !self.pads.contains_key(&note_id) {
+          break;
+        }
+        self.run_step(note_id, step);
+      }
+      self.show_pad(note_id);
+    }
+    self.notify_page_change();
+  }
+
+  fn run_step(&mut self, note_id: u8, step: Step) {
+    match step {
+      Step::Play { path, gain } => {
+        let volume = sequence::gain_to_volume(gain);
+        if let Some((voice, _)) = self.play_voice(path, volume) {
+          self.sinks.entry(note_id).or_default().push(voice);
+        }
+      }
+      Step::Wait(_) => {}
+      Step::Page(index) => self.goto_page(index),
+      Step::StopAll => {
+        for other_id in self.busy_pads().into_iter().filter(|other_id| *other_id != note_id) {
+          self.transition(other_id, PadEvent::StopAll);
+        }
+        for (audio_sink, virtual_sink) in self.sinks.remove(&note_id).into_iter().flatten() {
+          audio_sink.stop();
+          virtual_sink.stop();
+        }
+      }
+      Step::HoldMode(mode) => self.change_hold_mode(mode),
+      Step::NextTrack(playlist_id) => self.skip_track(playlist_id, true),
+      Step::PreviousTrack(playlist_id) => self.skip_track(playlist_id, false),
+    }
+  }
+
+  /// Record the press on a playing playlist, returns true if it has been pressed twice in a row.
+  /// None if the pad isn't a playing playlist.
+  fn playlist_press(&mut self, note_id: u8) -> Option<bool> {
+    let playing = self.pads.get(&note_id).is_some_and(|pad| pad.state == PadState::Playing);
+    match self.playlists.get_mut(&note_id) {
+      Some(queue) if playing => Some(queue.press(Instant::now(), DOUBLE_PRESS)),
+      _ => None,
+    }
+  }
+
+  /// Go to the next or previous track of a playing playlist, nothing happens at its ends.
+  fn skip_track(&mut self, note_id: u8, forward: bool) {
+    let Some(mut pad) = self.pads.get(&note_id).copied().filter(|pad| pad.state == PadState::Playing) else {
+      return;
+    };
+    let Some(mut queue) = self.playlists.remove(&note_id) else {
+      return;
+    };
+    if !queue.skip(forward) {
+      self.playlists.insert(note_id, queue);
+      return;
+    }
+    if self.play_track(&mut pad, queue) {
+      self.pads.insert(note_id, pad);
+      self.show_pad(note_id);
+    } else {
+      self.transition(note_id, PadEvent::StopAll);
+    }
+  }
+
+  /// Follow the playlists whose appended track started, and append the track after it.
+  fn advance_playlists(&mut self) {
+    let note_ids: Vec<u8> = self.playlists.keys().copied().collect();
+    for note_id in note_ids {
+      let Some(mut pad) = self.pads.get(&note_id).copied() else {
+        continue;
+      };
+      let (Some(playlist), Some(mut queue)) = (pad.note.playlist, self.playlists.remove(&note_id)) else {
+        continue;
+      };
+      let queued = self.sinks.get(&note_id).and_then(|voices| voices.last())
+        .map_or(0, |(audio_sink, virtual_sink)| audio_sink.queued().max(virtual_sink.queued()));
+      if queue.is_next_appended() && queued <= 1 {
+        let duration = queue.advance();
+        pad.shows_progress = self.progress.track(&self.config, note_id, duration);
+        self.pads.insert(note_id, pad);
+      }
+      if !queue.is_next_appended() {
+        self.append_next_track(note_id, playlist, &mut queue);
+      }
+      self.playlists.insert(note_id, queue);
+    }
+  }
+
+  /// Fade the music pad in over the other music pads playing, which fade out.
+  fn crossfade(&mut self, note_id: u8, length: Duration) {
+    let playing: Vec<u8> = self.pads.iter()
+      .filter(|(other_id, other)| **other_id != note_id && other.state == PadState::Playing && other.crossfade.is_some())
+      .map(|(other_id, _)| *other_id)
+      .collect();
+    if playing.is_empty() {
+      return;
+    }
+    let now = Instant::now();
+    self.fades.insert(note_id, Fade::new(now, length, 0.0, 1.0));
+    self.step_fades(now);
+    for other_id in playing {
+      self.transition(other_id, PadEvent::CrossFaded(length));
+    }
+  }
+
+  /// Crossfade of the note, its own or the one of the config for the music bookmarks.
+  fn crossfade_length(&self, note: &Note) -> Option<Duration> {
+    let bookmark = self.bookmark_number();
+    let bookmark_length = self.config.get_crossfade()
+      .filter(|crossfade| bookmark.is_some_and(|number| crossfade.bookmarks.contains(&number)))
+      .map(|crossfade| Duration::from_millis(crossfade.length));
+    note.crossfade.or(bookmark_length).filter(|length| !length.is_zero())
+  }
+
+  /// Change the volume of the fading pads, and stop the ones fading out once their fade is over.
+  fn step_fades(&mut self, now: Instant) {
+    self.fades.retain(|note_id, fade| {
+      let volume = fade.volume(now);
+      for (audio_sink, virtual_sink) in self.sinks.get(note_id).into_iter().flatten() {
+        audio_sink.set_volume(volume);
+        virtual_sink.set_volume(volume);
+        if fade.is_over(now) && fade.is_silent_at_end() {
+          audio_sink.stop();
+          virtual_sink.stop();
+        }
+      }
+      !fade.is_over(now)
+    });
+  }
+
+  /// Light the new page, then the pads that are still busy with a sound.
+  fn refresh_page(&mut self, with_header: bool) {
+    midi::refresh_grid(&self.launchpad, &self.config, &mut self.referential, &self.tx_midi, with_header);
+    for note_id in self.busy_pads() {
+      self.transition(note_id, PadEvent::PageChange);
+      self.show_pad(note_id);
+    }
+  }
+
+  fn show_pad(&self, note_id: u8) {
+    if self.page_overlay {
+      return;
+    }
+    let palette = self.launchpad.palette();
+    let command = match self.pads.get(&note_id) {
+      // Blink until the pad launches
+      Some(Pad { state: PadState::Queued, note, .. }) => LedCommand::flash(note_id, note.color, 0),
+      // The progress is displayed instead
+      Some(Pad { state: PadState::Playing, shows_progress: true, .. }) => return,
+      // How far the macro is
+      Some(Pad { state: PadState::Playing, .. }) if self.sequences.contains_key(&note_id) => {
+        LedCommand::static_color(note_id, progress::progress_color(&self.launchpad, self.sequences[&note_id].ratio()))
+      }
+      // Pulse in its own color while it is playing
+      Some(Pad { state: PadState::Playing, note, .. }) => LedCommand::pulse(note_id, note.color),
+      // Flash so it's visible that it can be resumed
+      Some(Pad { state: PadState::Paused, note, .. }) => LedCommand::flash(note_id, note.color, palette.yellow),
+      _ => self.referential.get_note(note_id).unwrap_or(Note::off(note_id)).into(),
+    };
+    self.tx_midi.send(command).unwrap();
+  }
+
+  /// Play the note of the pad on both buses, returns false if the sound couldn't be played.
+  fn play_sound(&mut self, pad: &mut Pad) -> bool {
+    let note = pad.note;
+    let Some(((audio_sink, virtual_sink), duration)) = self.play_voice(note.next_path(), 1.0) else {
+      return false;
+    };
+    pad.shows_progress = self.progress.track(&self.config, note.note_id, duration);
+
+    let voices = self.sinks.entry(note.note_id).or_default();
+    // The stopped and ended sounds are no longer needed, the retrigger policy says for the others
+    voices.retain(|(audio_sink, virtual_sink)| !(audio_sink.empty() && virtual_sink.empty()));
+    let kept = match note.retrigger {
+      Retrigger::Stack(copies) => copies - 1,
+      _ => 0,
+    };
+    while voices.len() > kept {
+      let (audio_sink, virtual_sink) = voices.remove(0);
+      audio_sink.stop();
+      virtual_sink.stop();
+    }
+    voices.push((audio_sink, virtual_sink));
+    true
+  }
+
+  /// Play the current track of the playlist on the pad, the sounds it played before are stopped.
+  fn play_track(&mut self, pad: &mut Pad, mut queue: Queue) -> bool {
+    let (note_id, Some(playlist)) = (pad.note.note_id, pad.note.playlist) else {
+      return false;
+    };
+    let Some((voice, duration)) = self.play_voice(playlist.track(queue.current()), 1.0) else {
+      return false;
+    };
+    pad.shows_progress = self.progress.track(&self.config, note_id, duration);
+    for (audio_sink, virtual_sink) in self.sinks.insert(note_id, vec![voice]).into_iter().flatten() {
+      audio_sink.stop();
+      virtual_sink.stop();
+    }
+    self.append_next_track(note_id, playlist, &mut queue);
+    self.playlists.insert(note_id, queue);
+    true
+  }
+
+  /// Append the next track to the sinks of the playlist, to play it right after the current one.
+  fn append_next_track(&self, note_id: u8, playlist: &Playlist, queue: &mut Queue) {
+    let (Some(next), Some((audio_sink, virtual_sink))) = (queue.next(), self.sinks.get(&note_id).and_then(|voices| voices.last())) else {
+      return;
+    };
+    let path = playlist.track(next);
+    let mut duration = None;
+    for (sink, volume) in [(audio_sink, VOICE_VOLUME), (virtual_sink, LOOPBACK_VOLUME)] {
+      // Nothing plays on an unavailable bus, its sink has to stay empty
+      if sink.empty() {
+        continue;
+      }
+      match sink.append(path, volume) {
+        Ok(track_duration) => duration = duration.or(track_duration),
+        Err(error) => error!("Unable to play {}: {}", path, error),
+      }
+    }
+    queue.set_next_appended(duration);
+  }
+
+  /// Play the sound on both buses at this volume multiplier, with its duration if it is known.
+  fn play_voice(&self, path: &str, volume: f32) -> Option<(Voice, Option<Duration>)> {
+    let (audio_sink, duration) = match self.output_bus.play_sound(path, VOICE_VOLUME * volume) {
+      Ok(played) => played,
+      Err(error) => {
+        error!("Unable to play {}: {}", path, error);
+        return None;
+      }
+    };
+    let (virtual_sink, virtual_duration) = match self.virtual_bus.play_sound(path, LOOPBACK_VOLUME * volume) {
+      Ok(played) => played,
+      Err(error) => {
+        error!("Unable to play {}: {}", path, error);
+        return None;
+      }
+    };
+    // The duration is unknown on an unavailable bus
+    Some(((audio_sink, virtual_sink), duration.or(virtual_duration)))
+  }
+}
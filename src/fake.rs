@@ -0,0 +1,246 @@
+//! In-memory audio and midi backends recording what the soundboard does, to test it without devices.
+//! The fakes are cheap handles on a shared state: keep a clone to inspect what the engine did with the other.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::audio::{AudioOutput, Playback, Played};
+use crate::midi::MidiPort;
+
+#[derive(Debug, Default)]
+struct PlaybackState {
+  paused: bool,
+  // Given to play the sound, the other volume is the multiplier set afterwards
+  play_volume: f32,
+  volume: Option<f32>,
+  stopped: bool,
+  ended: bool,
+  // The output was unplugged while the sound played
+  lost: bool,
+  position: Duration,
+  appended: Vec<String>,
+  // Appended sounds that didn't start yet
+  pending: usize,
+}
+
+/// Sound that never plays, its state is only changed by the calls and by the test.
+#[derive(Debug, Clone, Default)]
+pub struct FakePlayback {
+  state: Arc<Mutex<PlaybackState>>,
+}
+
+impl FakePlayback {
+  pub fn is_stopped(&self) -> bool {
+    self.state.lock().unwrap().stopped
+  }
+
+  /// Volume the sound was played at.
+  pub fn play_volume(&self) -> f32 {
+    self.state.lock().unwrap().play_volume
+  }
+
+  /// Volume multiplier set by the engine, 1 if it didn't.
+  pub fn volume(&self) -> f32 {
+    self.state.lock().unwrap().volume.unwrap_or(1.0)
+  }
+
+  /// Make the sound reach its end, the next appended one starts if there is one.
+  pub fn end(&self) {
+    let mut state = self.state.lock().unwrap();
+    state.position = Duration::ZERO;
+    if state.pending > 0 {
+      state.pending -= 1;
+    } else {
+      state.ended = true;
+    }
+  }
+
+  /// Paths of the sounds appended after the first one, in order.
+  pub fn appended(&self) -> Vec<String> {
+    self.state.lock().unwrap().appended.clone()
+  }
+
+  pub fn set_position(&self, position: Duration) {
+    self.state.lock().unwrap().position = position;
+  }
+}
+
+impl Playback for FakePlayback {
+  fn play(&self) {
+    self.state.lock().unwrap().paused = false;
+  }
+
+  fn pause(&self) {
+    self.state.lock().unwrap().paused = true;
+  }
+
+  fn stop(&self) {
+    self.state.lock().unwrap().stopped = true;
+  }
+
+  fn is_paused(&self) -> bool {
+    self.state.lock().unwrap().paused
+  }
+
+  fn set_volume(&self, volume: f32) {
+    self.state.lock().unwrap().volume = Some(volume);
+  }
+
+  fn empty(&self) -> bool {
+    let state = self.state.lock().unwrap();
+    state.stopped || state.ended || state.lost
+  }
+
+  fn position(&self) -> Duration {
+    self.state.lock().unwrap().position
+  }
+
+  fn append(&self, path: &str, _volume: f32) -> Result<Option<Duration>, Box<dyn Error>> {
+    let mut state = self.state.lock().unwrap();
+    state.appended.push(path.to_string());
+    state.pending += 1;
+    Ok(None)
+  }
+
+  fn queued(&self) -> usize {
+    let state = self.state.lock().unwrap();
+    if state.stopped || state.ended || state.lost { 0 } else { 1 + state.pending }
+  }
+}
+
+#[derive(Debug, Default)]
+struct OutputState {
+  available: bool,
+  duration: Option<Duration>,
+  played: Vec<(String, FakePlayback)>,
+}
+
+/// Audio output recording the sounds it is asked to play.
+#[derive(Debug, Clone)]
+pub struct FakeAudioOutput {
+  state: Arc<Mutex<OutputState>>,
+  // Availability seen by the last watch
+  watched: bool,
+}
+
+impl Default for FakeAudioOutput {
+  fn default() -> Self {
+    FakeAudioOutput::new()
+  }
+}
+
+impl FakeAudioOutput {
+  pub fn new() -> Self {
+    FakeAudioOutput {
+      state: Arc::new(Mutex::new(OutputState {
+        available: true,
+        ..OutputState::default()
+      })),
+      watched: true,
+    }
+  }
+
+  /// Duration given to the sounds played from now on, unknown by default.
+  pub fn set_duration(&self, duration: Option<Duration>) {
+    self.state.lock().unwrap().duration = duration;
+  }
+
+  /// Simulate the device being unplugged (false) or plugged back (true).
+  /// The sounds playing when it is unplugged end, even once it is back.
+  pub fn set_available(&self, available: bool) {
+    let mut state = self.state.lock().unwrap();
+    state.available = available;
+    if !available {
+      for (_, playback) in &state.played {
+        playback.state.lock().unwrap().lost = true;
+      }
+    }
+  }
+
+  /// Paths of the played sounds, in order.
+  pub fn played(&self) -> Vec<String> {
+    self.state.lock().unwrap().played.iter().map(|(path, _)| path.clone()).collect()
+  }
+
+  /// Every sound played from the path, in order.
+  pub fn playbacks(&self, path: &str) -> Vec<FakePlayback> {
+    let state = self.state.lock().unwrap();
+    state.played.iter().filter(|(played, _)| played == path).map(|(_, playback)| playback.clone()).collect()
+  }
+
+  /// The last sound played from the path.
+  pub fn playback(&self, path: &str) -> Option<FakePlayback> {
+    let state = self.state.lock().unwrap();
+    state.played.iter().rev().find(|(played, _)| played == path).map(|(_, playback)| playback.clone())
+  }
+}
+
+impl AudioOutput for FakeAudioOutput {
+  fn open(_name: &str) -> Option<Self> {
+    Some(FakeAudioOutput::new())
+  }
+
+  fn is_available(&self) -> bool {
+    self.state.lock().unwrap().available
+  }
+
+  fn watch(&mut self) -> bool {
+    let available = self.is_available();
+    let changed = available != self.watched;
+    self.watched = available;
+    changed
+  }
+
+  fn play_sound(&self, path: &str, volume: f32) -> Result<Played, Box<dyn Error>> {
+    let mut state = self.state.lock().unwrap();
+    let playback = FakePlayback::default();
+    playback.state.lock().unwrap().play_volume = volume;
+    // Like an unavailable device, nothing plays
+    if !state.available {
+      playback.stop();
+    }
+    state.played.push((path.to_string(), playback.clone()));
+    Ok((Box::new(playback), state.duration))
+  }
+}
+
+#[derive(Debug, Default)]
+struct PortState {
+  sent: Vec<Vec<u8>>,
+  received: VecDeque<Vec<u8>>,
+}
+
+/// Midi port recording the sent messages, the received ones are pushed by the test.
+#[derive(Debug, Clone, Default)]
+pub struct FakeMidiPort {
+  state: Arc<Mutex<PortState>>,
+}
+
+impl FakeMidiPort {
+  pub fn new() -> Self {
+    FakeMidiPort::default()
+  }
+
+  /// Queue a message as if the device sent it.
+  pub fn push(&self, message: &[u8]) {
+    self.state.lock().unwrap().received.push_back(message.to_vec());
+  }
+
+  pub fn sent(&self) -> Vec<Vec<u8>> {
+    self.state.lock().unwrap().sent.clone()
+  }
+}
+
+impl MidiPort for FakeMidiPort {
+  fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+    self.state.lock().unwrap().sent.push(message.to_vec());
+    Ok(())
+  }
+
+  /// Returns right away, the timeout is ignored.
+  fn receive(&mut self, _timeout: Duration) -> Option<Vec<u8>> {
+    self.state.lock().unwrap().received.pop_front()
+  }
+}
@@ -1,91 +1,361 @@
-pub enum Launchpad {
-  MiniMk2,
-  MiniMk3,
-  X
+use std::fs;
+use std::path::Path;
+
+use log::{debug, warn};
+use midir::MidiOutputConnection;
+use serde::{Deserialize, Serialize};
+
+use crate::{NoteEvent, midi};
+use crate::config::Config;
+use crate::midi::DeviceIdentity;
+use crate::referential::Note;
+
+// Profiles shipped with the application, a profile with the same name in the user folder replaces them.
+const EMBEDDED_PROFILES: [&str; 4] = [
+  include_str!("profiles/mini_mk2.yaml"),
+  include_str!("profiles/mini_mk3.yaml"),
+  include_str!("profiles/pro_mk3.yaml"),
+  include_str!("profiles/x.yaml"),
+];
+// The Mini MK3, which the application is tested with
+const DEFAULT_PROFILE: usize = 1;
+
+/// Description of a launchpad model: its notes layout, control buttons, and how to light it.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Launchpad {
+  name: String,
+  // Substrings of the midi port name used to recognize the model
+  #[serde(default)]
+  port_names: Vec<String>,
+  // Reply expected to the Universal Device Inquiry, preferred over the port name
+  device_inquiry: Option<DeviceInquiry>,
+  #[serde(default)]
+  programmer_mode_command: Vec<u8>,
+  // Gives the device back to its standalone mode when the application stops
+  live_mode_command: Option<Vec<u8>>,
+  text_scroll_header: Option<Vec<u8>>,
+  #[serde(default)]
+  input: InputFormat,
+  grid: Vec<Vec<u8>>,
+  #[serde(default)]
+  top_buttons: Vec<u8>,
+  #[serde(default)]
+  side_buttons: Vec<u8>,
+  controls: Controls,
+  // None for devices without LEDs
+  led: Option<LedFormat>,
+  #[serde(default)]
+  palette: Palette,
 }
 
-impl Launchpad {
-  pub(crate) fn first_page_note(&self) -> u8 {
-    match self {
-      Launchpad::MiniMk2 => 104,
-      Launchpad::MiniMk3 => 91,
-      Launchpad::X => 91
+/// Midi messages sent by the device when a pad or a button is pressed.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct InputFormat {
+  #[serde(default = "enabled")]
+  notes: bool,
+  #[serde(default = "enabled")]
+  control_changes: bool,
+}
+
+fn enabled() -> bool {
+  true
+}
+
+impl Default for InputFormat {
+  fn default() -> Self {
+    InputFormat {
+      notes: true,
+      control_changes: true,
     }
   }
+}
 
-  pub(crate) fn last_page_note(&self) -> u8 {
-    match self {
-      Launchpad::MiniMk2 => 105,
-      Launchpad::MiniMk3 => 92,
-      Launchpad::X => 92
+impl InputFormat {
+  /// Convert a midi message into a press or release event, None if it isn't handled.
+  pub fn parse(&self, message: &[u8]) -> Option<NoteEvent> {
+    if message.len() != 3 {
+      return None;
+    }
+    // Any velocity or value presses the pad, whatever the midi channel
+    match message[0] & 0xF0 {
+      0x80 if self.notes => Some((message[1], false)),
+      0x90 if self.notes => Some((message[1], message[2] > 0)),
+      0xB0 if self.control_changes => Some((message[1], message[2] > 0)),
+      _ => None,
     }
   }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct DeviceInquiry {
+  manufacturer: Vec<u8>,
+  family: [u8; 2],
+  model: [u8; 2],
+}
+
+/// Buttons triggering the actions of the application.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Controls {
+  pub first_page: u8,
+  pub last_page: u8,
+  pub previous_page: u8,
+  pub next_page: u8,
+  pub end_session: u8,
+  pub swap_hold_mode: u8,
+  pub stop: u8,
+  #[serde(default)]
+  pub bookmarks: Vec<u8>,
+  // Buttons jumping directly to a page of the current bookmark
+  #[serde(default)]
+  pub pages: Vec<u8>,
+  // Lit in red while the audio device is unavailable
+  pub output_status: Option<u8>,
+  pub virtual_status: Option<u8>,
+}
+
+/// Midi channels selecting the lighting mode of a pad.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LedFormat {
+  pub static_channel: u8,
+  pub flash_channel: Option<u8>,
+  pub pulse_channel: Option<u8>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Palette {
+  pub white: u8,
+  pub red: u8,
+  pub yellow: u8,
+  pub green: u8,
+  // Colors from the start to the end of a sound
+  pub progress: Vec<u8>,
+}
 
-  pub(crate) fn prev_page_note(&self) -> u8 {
-    match self {
-      Launchpad::MiniMk2 => 106,
-      Launchpad::MiniMk3 => 93,
-      Launchpad::X => 93
+impl Default for Palette {
+  fn default() -> Self {
+    Palette {
+      white: 3,
+      red: 5,
+      yellow: 13,
+      green: 87,
+      progress: vec![21, 17, 13, 9, 5],
     }
   }
+}
 
-  pub(crate) fn next_page_note(&self) -> u8 {
-    match self {
-      Launchpad::MiniMk2 => 107,
-      Launchpad::MiniMk3 => 94,
-      Launchpad::X => 94
-    }
+impl Palette {
+  pub fn white(&self, note_id: u8) -> Note {
+    Note::new(note_id, "", self.white)
   }
 
-  pub(crate) fn end_session_note(&self) -> u8 {
-    match self {
-      Launchpad::MiniMk2 => 108,
-      Launchpad::MiniMk3 => 95,
-      Launchpad::X => 95
-    }
+  pub fn red(&self, note_id: u8) -> Note {
+    Note::new(note_id, "", self.red)
+  }
+
+  pub fn yellow(&self, note_id: u8) -> Note {
+    Note::new(note_id, "", self.yellow)
+  }
+
+  pub fn green(&self, note_id: u8) -> Note {
+    Note::new(note_id, "", self.green)
+  }
+}
+
+impl Launchpad {
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn controls(&self) -> &Controls {
+    &self.controls
   }
 
-  pub(crate) fn stop_note(&self) -> u8 {
-    match self {
-      Launchpad::MiniMk2 => 19,
-      Launchpad::MiniMk3 => 19,
-      Launchpad::X => 19
+  /// Replace the controls of the profile by the ones saved by the learn mode, if any.
+  pub fn override_controls(&mut self, path: &str) {
+    if !Path::new(path).is_file() {
+      return;
     }
+    let content = match fs::read_to_string(path) {
+      Ok(content) => content,
+      Err(error) => {
+        warn!("Unable to read the learned controls {}: {}", path, error);
+        return;
+      }
+    };
+    match serde_yaml::from_str::<Controls>(&content) {
+      Ok(controls) => {
+        debug!("Using the controls learned in {}", path);
+        self.controls = controls;
+      }
+      Err(error) => warn!("Invalid learned controls {}: {}", path, error),
+    }
+  }
+
+  pub fn first_page_note(&self) -> u8 {
+    self.controls.first_page
+  }
+
+  pub fn last_page_note(&self) -> u8 {
+    self.controls.last_page
+  }
+
+  pub fn prev_page_note(&self) -> u8 {
+    self.controls.previous_page
   }
 
-  pub(crate) fn bookmark_notes<'array>(&self) -> &'array[u8] {
-    match self {
-      Launchpad::MiniMk2 => &[89, 79, 69, 59, 49, 39, 29],
-      Launchpad::MiniMk3 => &[89, 79, 69, 59, 49, 39, 29],
-      Launchpad::X => &[89, 79, 69, 59, 49, 39, 29]
+  pub fn next_page_note(&self) -> u8 {
+    self.controls.next_page
+  }
+
+  pub fn end_session_note(&self) -> u8 {
+    self.controls.end_session
+  }
+
+  pub fn stop_note(&self) -> u8 {
+    self.controls.stop
+  }
+
+  pub fn bookmark_notes(&self) -> &[u8] {
+    &self.controls.bookmarks
+  }
+
+  pub fn output_status_note(&self) -> Option<u8> {
+    self.controls.output_status
+  }
+
+  pub fn virtual_status_note(&self) -> Option<u8> {
+    self.controls.virtual_status
+  }
+
+  pub fn page_notes(&self) -> &[u8] {
+    &self.controls.pages
+  }
+
+  /// Notes of the grid, from the top left pad to the bottom right pad.
+  pub fn grid_notes(&self) -> Vec<u8> {
+    self.grid.iter().flatten().copied().collect()
+  }
+
+  pub fn progress_strip_notes(&self) -> &[u8] {
+    self.grid.last().map_or(&[], |row| row.as_slice())
+  }
+
+  /// Every note that can be lit, the top buttons being included only if requested.
+  pub fn lit_notes(&self, with_header: bool) -> Vec<u8> {
+    let mut notes = self.grid_notes();
+    notes.extend_from_slice(&self.side_buttons);
+    if with_header {
+      notes.extend_from_slice(&self.top_buttons);
     }
+    notes
+  }
+
+  pub fn programmer_mode_command(&self) -> &[u8] {
+    &self.programmer_mode_command
   }
 
-  pub(crate) fn programmer_mode_command<'array>(&self) -> &'array[u8] {
-    match self {
-      Launchpad::MiniMk2 => &[240, 0, 32, 41, 2, 24, 34, 0, 247],
-      Launchpad::MiniMk3 => &[240, 0, 32, 41, 2, 13, 14, 1, 247],
-      Launchpad::X => &[240, 0, 32, 41, 2, 12, 127, 247]
+  pub fn live_mode_command(&self) -> Option<&[u8]> {
+    self.live_mode_command.as_deref()
+  }
+
+  /// SysEx to scroll a text across the grid, None if the model doesn't support it.
+  /// Only ASCII characters are displayed, an empty text stops the current scrolling.
+  pub fn text_scroll_command(&self, text: &str, color: u8, speed: u8) -> Option<Vec<u8>> {
+    let mut command = self.text_scroll_header.clone()?;
+    if !text.is_empty() {
+      // No loop, speed, then palette color
+      command.extend_from_slice(&[0, speed, 0, color]);
+      command.extend(text.chars().filter(|c| c.is_ascii() && !c.is_ascii_control()).map(|c| c as u8));
     }
+    command.push(247);
+    Some(command)
+  }
+
+  pub fn swap_hold_mode_note(&self) -> u8 {
+    self.controls.swap_hold_mode
+  }
+
+  pub fn led_format(&self) -> Option<&LedFormat> {
+    self.led.as_ref()
+  }
+
+  pub fn input_format(&self) -> &InputFormat {
+    &self.input
+  }
+
+  pub fn palette(&self) -> &Palette {
+    &self.palette
   }
 
-  pub(crate) fn swap_hold_mode_note(&self) -> u8 {
-    match self {
-      Launchpad::MiniMk2 => 111,
-      Launchpad::MiniMk3 => 98,
-      Launchpad::X => 98
+  /// Select the profile from the config, the device inquiry reply, or the midi port name, in that order.
+  pub fn select(config: &Config, conn_out: Option<&mut MidiOutputConnection>) -> Option<Launchpad> {
+    let midi_in_device_name = config.get_midi_in_device()?;
+    if let Some(path) = config.get_controller_profile() {
+      return Launchpad::load_profile(path);
     }
+    let profiles = Launchpad::load_profiles("launchpads");
+    // Ask the device which model it is, the port name is only used if it doesn't answer
+    conn_out
+      .and_then(|conn_out| midi::identify_device(midi_in_device_name, conn_out))
+      .and_then(|identity| Launchpad::get_launchpad_by_identity(&profiles, &identity))
+      .or_else(|| Launchpad::get_launchpad(&profiles, midi_in_device_name))
   }
 
-  pub fn get_launchpad(name: &str) -> Launchpad {
-    if name.contains("LPMiniMK2") {
-      Launchpad::MiniMk2
-    } else if name.contains("LPMiniMK3") {
-      Launchpad::MiniMk3
-    } else if name.contains("LPX") {
-      Launchpad::X
-    } else {
-      panic!("Launchpad not found")
+  /// Profile used when the device can't be identified, such as with virtual midi ports.
+  pub fn default_profile() -> Option<Launchpad> {
+    serde_yaml::from_str(EMBEDDED_PROFILES[DEFAULT_PROFILE]).ok()
+  }
+
+  /// Load a single profile, such as the mapping of a midi controller that isn't a launchpad.
+  pub fn load_profile(path: &str) -> Option<Launchpad> {
+    let content = fs::read_to_string(path).map_err(|error| warn!("Unable to read profile {}: {}", path, error)).ok()?;
+    serde_yaml::from_str(&content).map_err(|error| warn!("Invalid profile {}: {}", path, error)).ok()
+  }
+
+  /// Load the embedded profiles and the ones found in the given folder, user profiles come first.
+  pub fn load_profiles(folder: &str) -> Vec<Launchpad> {
+    let mut profiles: Vec<Launchpad> = vec![];
+    if Path::new(folder).is_dir() {
+      let entries = fs::read_dir(folder).map_err(|error| warn!("Unable to read the profiles of {}: {}", folder, error));
+      // The unreadable files are skipped, such as the ones a file manager leaves behind
+      for path in entries.into_iter().flatten().filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        if !path.is_file() {
+          continue;
+        }
+        let Ok(content) = fs::read_to_string(&path).map_err(|error| warn!("Unable to read launchpad profile {}: {}", path.display(), error)) else {
+          continue;
+        };
+        match serde_yaml::from_str::<Launchpad>(&content) {
+          Ok(profile) => {
+            debug!("Loaded launchpad profile {} from {}", profile.name, path.display());
+            profiles.push(profile);
+          }
+          Err(error) => warn!("Invalid launchpad profile {}: {}", path.display(), error),
+        }
+      }
+    }
+    for content in EMBEDDED_PROFILES {
+      let profile: Launchpad = serde_yaml::from_str(content).expect("Invalid embedded launchpad profile");
+      if !profiles.iter().any(|p| p.name == profile.name) {
+        profiles.push(profile);
+      }
     }
+    profiles
   }
-}
\ No newline at end of file
+
+  /// Find the profile matching the identity answered by the device.
+  pub fn get_launchpad_by_identity(profiles: &[Launchpad], identity: &DeviceIdentity) -> Option<Launchpad> {
+    profiles.iter()
+      .find(|profile| profile.device_inquiry.as_ref().is_some_and(|inquiry| {
+        inquiry.manufacturer == identity.manufacturer && inquiry.family == identity.family && inquiry.model == identity.model
+      }))
+      .cloned()
+  }
+
+  /// Find the profile matching the midi port name.
+  pub fn get_launchpad(profiles: &[Launchpad], name: &str) -> Option<Launchpad> {
+    profiles.iter()
+      .find(|profile| profile.port_names.iter().any(|port_name| name.contains(port_name.as_str())))
+      .cloned()
+  }
+}
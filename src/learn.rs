@@ -0,0 +1,103 @@
+use std::error::Error;
+use std::fs;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use log::error;
+
+use crate::{LEARNED_CONTROLS_PATH, midi};
+use crate::config::Config;
+use crate::launchpad::{Controls, InputFormat, Launchpad};
+use crate::midi::{MidiPort, MidirPort};
+
+// How long to wait for a message before waiting again
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(1);
+// Longest delay between the two presses that keep the note of the profile
+const DOUBLE_PRESS: Duration = Duration::from_millis(400);
+
+/// Ask for each action to press the button that should trigger it, then save the controls.
+/// The saved controls replace the ones of the launchpad profile on the next start.
+pub fn run(config: &Config) -> Result<(), Box<dyn Error>> {
+  let mut conn_out = config.get_midi_out_device().and_then(midi::select_midi_output_device);
+  let Some(launchpad) = Launchpad::select(config, conn_out.as_mut()) else {
+    error!("No launchpad profile matches {}, you can add one in the launchpads folder", config.get_midi_in_device().unwrap_or_default());
+    return Ok(());
+  };
+
+  // Buttons send different messages outside the programmer mode
+  let has_output = conn_out.is_some();
+  let mut port = MidirPort::new(conn_out);
+  if has_output && !launchpad.programmer_mode_command().is_empty() {
+    port.send(launchpad.programmer_mode_command())?;
+  }
+
+  // Stop learning on Ctrl+C, the launchpad is given back in live mode either way
+  let running = Arc::new(AtomicBool::new(true));
+  let signal_running = running.clone();
+  ctrlc::set_handler(move || signal_running.store(false, Ordering::Relaxed))?;
+
+  let controls = if port.listen(config.get_midi_in_device().unwrap()) {
+    learn_controls(&mut port, &launchpad, &running)
+  } else {
+    error!("No midi input port found, have you plugged your midi device ?");
+    None
+  };
+
+  if has_output && let Some(command) = launchpad.live_mode_command() {
+    port.send(command)?;
+  }
+  if let Some(controls) = controls {
+    save(&controls)?;
+    println!("Controls saved in {}", LEARNED_CONTROLS_PATH);
+  }
+  Ok(())
+}
+
+/// Learn the button of each action, None if interrupted.
+fn learn_controls(port: &mut dyn MidiPort, launchpad: &Launchpad, running: &AtomicBool) -> Option<Controls> {
+  let input_format = launchpad.input_format();
+  let mut controls = launchpad.controls().clone();
+  controls.stop = learn(port, input_format, "stop all sounds", controls.stop, running)?;
+  controls.next_page = learn(port, input_format, "next page", controls.next_page, running)?;
+  controls.previous_page = learn(port, input_format, "previous page", controls.previous_page, running)?;
+  controls.first_page = learn(port, input_format, "first page", controls.first_page, running)?;
+  controls.last_page = learn(port, input_format, "last page", controls.last_page, running)?;
+  controls.end_session = learn(port, input_format, "end session", controls.end_session, running)?;
+  controls.swap_hold_mode = learn(port, input_format, "swap hold mode", controls.swap_hold_mode, running)?;
+  // As many bookmarks as the profile has, such as the 15 of the Pro MK3
+  controls.bookmarks = launchpad.bookmark_notes().iter().enumerate()
+    .map(|(index, &current)| learn(port, input_format, &format!("bookmark {}", index + 1), current, running))
+    .collect::<Option<_>>()?;
+  Some(controls)
+}
+
+/// Wait for the next pressed button, pressing a button twice in a row keeps the current note.
+/// None if interrupted.
+fn learn(port: &mut dyn MidiPort, input_format: &InputFormat, action: &str, current: u8, running: &AtomicBool) -> Option<u8> {
+  println!("Press the button for: {} (press any button twice to keep {})", action, current);
+  let mut pressed: Option<(u8, Instant)> = None;
+  while running.load(Ordering::Relaxed) {
+    let timeout = pressed.map_or(RECEIVE_TIMEOUT, |(_, at)| DOUBLE_PRESS.saturating_sub(at.elapsed()));
+    let event = port.receive(timeout).and_then(|message| input_format.parse(&message));
+    match (event, pressed) {
+      (Some((note_id, true)), Some((previous, _))) if note_id == previous => {
+        println!("  -> {} (kept)", current);
+        return Some(current);
+      }
+      (Some((note_id, true)), _) => pressed = Some((note_id, Instant::now())),
+      _ => {}
+    }
+    // No second press came, the button is learned
+    if let Some((note_id, at)) = pressed && at.elapsed() >= DOUBLE_PRESS {
+      println!("  -> {}", note_id);
+      return Some(note_id);
+    }
+  }
+  None
+}
+
+fn save(controls: &Controls) -> Result<(), Box<dyn Error>> {
+  fs::write(LEARNED_CONTROLS_PATH, serde_yaml::to_string(controls)?)?;
+  Ok(())
+}
@@ -0,0 +1,112 @@
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::launchpad::{Launchpad, LedFormat};
+use crate::midi::MidiPort;
+use crate::referential::Note;
+
+// Minimum delay between two reopenings of the port after a failed send, while the device is unplugged
+const REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Lighting command sent to the LED thread for a single pad.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LedCommand {
+  /// Light the pad with a fixed color.
+  Static { note_id: u8, color: u8 },
+  /// Alternate between two colors, the launchpad uses the static color as the first one.
+  Flash { note_id: u8, from: u8, to: u8 },
+  /// Fade the color in and out.
+  Pulse { note_id: u8, color: u8 },
+  /// SysEx message specific to the launchpad model, such as scrolling text.
+  SysEx(Vec<u8>),
+  /// Open the port again, the device has been plugged back.
+  Reconnect,
+  /// Stop the LED thread once the previous commands are sent.
+  Shutdown,
+}
+
+impl LedCommand {
+  pub fn static_color(note_id: u8, color: u8) -> Self {
+    LedCommand::Static { note_id, color }
+  }
+
+  pub fn flash(note_id: u8, from: u8, to: u8) -> Self {
+    LedCommand::Flash { note_id, from, to }
+  }
+
+  pub fn pulse(note_id: u8, color: u8) -> Self {
+    LedCommand::Pulse { note_id, color }
+  }
+
+  /// Midi messages to send to the launchpad to apply the command.
+  /// Lighting modes not supported by the model fall back to a static color.
+  pub fn messages(&self, format: &LedFormat) -> Vec<Vec<u8>> {
+    match self {
+      LedCommand::Static { note_id, color } => vec![vec![format.static_channel, *note_id, *color]],
+      LedCommand::Flash { note_id, from, to } => match format.flash_channel {
+        Some(channel) => vec![
+          vec![format.static_channel, *note_id, *from],
+          vec![channel, *note_id, *to],
+        ],
+        None => vec![vec![format.static_channel, *note_id, *from]],
+      },
+      LedCommand::Pulse { note_id, color } => {
+        vec![vec![format.pulse_channel.unwrap_or(format.static_channel), *note_id, *color]]
+      }
+      LedCommand::SysEx(message) => vec![message.clone()],
+      LedCommand::Reconnect | LedCommand::Shutdown => vec![],
+    }
+  }
+}
+
+impl From<Note> for LedCommand {
+  fn from(note: Note) -> Self {
+    LedCommand::static_color(note.note_id, note.color)
+  }
+}
+
+impl From<&Note> for LedCommand {
+  fn from(note: &Note) -> Self {
+    LedCommand::static_color(note.note_id, note.color)
+  }
+}
+
+/// Send the LED commands to the launchpad until the Shutdown command, they are dropped if it has no LEDs.
+/// The port is given by `reopen` again on the Reconnect command and when sending fails, as the device may have been unplugged.
+/// The commands are dropped while it can't be reopened, with a new try at most once per interval.
+pub fn forward(rx_midi: Receiver<LedCommand>, launchpad: &Launchpad, mut port: Option<Box<dyn MidiPort>>, reopen: impl Fn() -> Option<Box<dyn MidiPort>>) {
+  let mut last_reopen: Option<Instant> = None;
+  for command in rx_midi {
+    match command {
+      LedCommand::Shutdown => break,
+      // Sending on the previous connection can succeed without reaching the replugged device
+      LedCommand::Reconnect => {
+        port = reopen().or(port);
+        last_reopen = Some(Instant::now());
+        continue;
+      }
+      _ => {}
+    }
+    let Some(led_format) = launchpad.led_format() else {
+      continue;
+    };
+    for message in command.messages(led_format) {
+      if let Some(port) = port.as_mut() && port.send(&message).is_ok() {
+        continue;
+      }
+      if last_reopen.is_some_and(|last_reopen| last_reopen.elapsed() < REOPEN_INTERVAL) {
+        port = None;
+        continue;
+      }
+      // Retry once on the reopened port
+      port = reopen();
+      last_reopen = Some(Instant::now());
+      if let Some(reopened) = port.as_mut() && let Err(error) = reopened.send(&message) {
+        warn!("Error sending midi message: {}", error);
+        port = None;
+      }
+    }
+  }
+}
@@ -0,0 +1,25 @@
+//! Soundboard playing sounds from the pads of a launchpad, on the speakers and on a virtual audio device.
+//! The binary only wires the devices to the [`engine::Engine`], other tools can embed it the same way.
+
+pub mod audio;
+pub mod config;
+pub mod engine;
+#[cfg(feature = "fake")]
+pub mod fake;
+pub mod launchpad;
+pub mod learn;
+pub mod led;
+pub mod midi;
+pub mod mirror;
+pub mod pad;
+pub mod playlist;
+pub mod progress;
+pub mod quantize;
+pub mod referential;
+pub mod sequence;
+pub mod variation;
+
+pub const LEARNED_CONTROLS_PATH: &str = "controls.yaml";
+
+/// A pad or button of the launchpad, pressed (true) or released (false).
+pub type NoteEvent = (u8, bool);
@@ -1,47 +1,41 @@
 extern crate core;
 
-use std::collections::HashMap;
+use std::env;
 use std::error::Error;
 use std::sync::{Arc, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use config_file::FromConfigFile;
-use log::{debug, error};
-use rodio::{OutputStream, Sink};
-
-use crate::config::{Config, HoldMode};
-use crate::launchpad::Launchpad;
-use crate::referential::{Note, Referential};
-
-mod config;
-mod referential;
-mod audio;
-mod midi;
-mod launchpad;
-
-const WHITE_COLOR: u8 = 3;
-const RED_COLOR: u8 = 5;
-const YELLOW_COLOR: u8 = 13;
-const GREEN_COLOR: u8 = 87;
-const VOICE_VOLUME: f32 = 1.0;
-const LOOPBACK_VOLUME: f32 = 0.10;
-
-// Midi Output Event for the launchpad is a tuple of 3 elements:
-// - The note id
-// - The color
-// - A boolean to know if the light should blink or not
-type MidiOutputEvent = (u8, u8, bool);
-type NoteEvent = (u8, bool);
+use log::{debug, error, info, warn};
+
+use launch_soundpad::{LEARNED_CONTROLS_PATH, audio, learn, led, midi};
+use launch_soundpad::audio::{AudioOutput, Bus};
+use launch_soundpad::config::Config;
+use launch_soundpad::engine::Engine;
+use launch_soundpad::launchpad::Launchpad;
+use launch_soundpad::led::LedCommand;
+use launch_soundpad::midi::{MidiPort, MidirPort};
+use launch_soundpad::quantize::MidiClock;
+use launch_soundpad::referential::Referential;
+
+// Delay before listening again to a replugged device that isn't ready yet
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
 
 fn main() -> Result<(), Box<dyn Error>> {
 
   // TODO: Load the configuration file from config.yaml, if not found generate a default one
   // Also it should be initialized inside the config module.
-  let mut config = Config::from_config_file("config.yaml").unwrap();
+  let config = Config::from_config_file("config.yaml").unwrap();
 
   env_logger::init();
 
+  if env::args().nth(1).as_deref() == Some("learn") {
+    return learn::run(&config);
+  }
+
   let mut midi_in = midir::MidiInput::new("midir reading input")?;
   midi_in.ignore(midir::Ignore::All);
 
@@ -50,53 +44,63 @@ fn main() -> Result<(), Box<dyn Error>> {
     debug!("  - {}", p);
   }
 
-  let mut conn_out;
-
-  match midi::select_midi_output_device(config.get_midi_out_device().unwrap()) {
-    Some(conn) => {
-      conn_out = conn;
+  // With virtual ports, the application creates its own ports instead of connecting to the device
+  let virtual_midi = env::args().any(|arg| arg == "--virtual-midi");
+
+  // Devices without LEDs may not have an output port
+  let mut conn_out = if virtual_midi {
+    midi::create_virtual_output()
+  } else {
+    config.get_midi_out_device().and_then(midi::select_midi_output_device)
+  };
+
+  let midi_in_device_name = config.get_midi_in_device().unwrap_or_default().to_string();
+  let launchpad = if virtual_midi {
+    Launchpad::select(&config, None).or_else(Launchpad::default_profile)
+  } else {
+    Launchpad::select(&config, conn_out.as_mut())
+  };
+  let launchpad = match launchpad {
+    Some(mut launchpad) => {
+      launchpad.override_controls(LEARNED_CONTROLS_PATH);
+      Arc::new(launchpad)
     }
-    _ => {
-      error!("No midi output port found, have you plugged your midi device ?");
+    None => {
+      error!("No launchpad profile matches {}, you can add one in the launchpads folder", midi_in_device_name);
       return Ok(());
     }
+  };
+  if conn_out.is_none() && launchpad.led_format().is_some() {
+    error!("No midi output port found, have you plugged your midi device ?");
+    return Ok(());
   }
-
-  let midi_in_device_name = config.get_midi_in_device().unwrap();
-  let launchpad = Arc::new(Launchpad::get_launchpad(midi_in_device_name));
+  debug!("Using launchpad profile {}", launchpad.name());
 
   debug!("Available output ports:");
   for p in midi::get_midi_output_devices() {
     debug!("  - {}", p);
   }
 
-  let output_handle;
-
-  match audio::select_output_device(config.get_output_device().unwrap()) {
-    Some(stream) => {
-      output_handle = stream;
-    }
+  let output_bus = match Bus::open(config.get_output_device().unwrap()) {
+    Some(stream) => stream,
     None => {
       error!("No audio device found");
       return Ok(());
     }
-  }
+  };
 
   debug!("Available output devices:");
   for device in audio::get_output_devices() {
     debug!("  - {}", device);
   }
 
-  let virtual_handle;
-  match audio::select_output_device(&config.get_virtual_device().unwrap()) {
-    Some(handle) => {
-      virtual_handle = handle;
-    }
+  let virtual_bus = match Bus::open(&config.get_virtual_device().unwrap()) {
+    Some(bus) => bus,
     None => {
       error!("No virtual device found");
       return Ok(());
     }
-  }
+  };
 
   let mut referential = Referential::new(&launchpad);
   referential.init(String::from("pages"));
@@ -106,144 +110,92 @@ fn main() -> Result<(), Box<dyn Error>> {
     return Ok(());
   }
 
-  let (tx_note, rx_note): (Sender<NoteEvent>, Receiver<NoteEvent>) = mpsc::channel();
-  let (tx_midi, rx_midi): (Sender<MidiOutputEvent>, Receiver<MidiOutputEvent>) = mpsc::channel();
-
-  midi::refresh_grid(&launchpad, &config, &mut referential, &tx_midi, true);
+  let (tx_midi, rx_midi): (Sender<LedCommand>, Receiver<LedCommand>) = mpsc::channel();
 
   // Activate programmer mode
-  conn_out.send(launchpad.programmer_mode_command()).unwrap();
-
-  // Thread to manage the midi LEDs
-  thread::spawn(move || {
-    for (note_id, color, should_blink) in rx_midi {
-      let channel = if should_blink { 145 } else { 144 };
-      let color = if should_blink { WHITE_COLOR } else { color };
-      conn_out.send(&[channel, note_id, color]).unwrap();
-    }
-  });
+  if let Some(conn_out) = conn_out.as_mut() && !launchpad.programmer_mode_command().is_empty() {
+    conn_out.send(launchpad.programmer_mode_command()).unwrap();
+  }
 
-  let _conn_in = midi::listen_midi_input(midi_in_device_name, tx_note);
-  let mut sinks: HashMap<u8, (Sink, Sink)> = HashMap::new();
+  // Thread to manage the midi LEDs, the commands are dropped if the device has none
+  let thread_launchpad = launchpad.clone();
+  let midi_out_device_name = config.get_midi_out_device().filter(|_| !virtual_midi).map(String::from);
+  let led_port = conn_out.map(|conn_out| Box::new(MidirPort::new(Some(conn_out))) as Box<dyn MidiPort>);
+  let led_thread = thread::spawn(move || {
+    led::forward(rx_midi, &thread_launchpad, led_port, || {
+      let conn_out = midi_out_device_name.as_deref().and_then(midi::select_midi_output_device)?;
+      Some(Box::new(MidirPort::new(Some(conn_out))))
+    });
+  });
 
-  // Main loop to receive the midi events, also block the main thread from exiting.
-  for (note_id, is_on) in rx_note {
-    if !is_on {
-      match config.get_hold_to_mode() {
-        HoldMode::Normal => {
-          continue;
-        }
-        HoldMode::Stop => {
-          if let Some((audio_sink, virtual_sink)) = sinks.remove(&note_id) {
-            audio_sink.stop();
-            virtual_sink.stop();
-
-          }
-        }
-        HoldMode::Pause => {
-          if let Some((audio_sink, virtual_sink)) = sinks.get(&note_id) {
-            audio_sink.pause();
-            virtual_sink.pause();
-          }
-        }
-      }
-      if let Some(note) = referential.get_note(note_id) {
-        let thread_tx_midi = tx_midi.clone();
-        thread_tx_midi.send((note.note_id, note.color, false)).unwrap();
-      }
+  // The input is closed while the device is unplugged
+  let mut input_port = MidirPort::new(None);
+  // When to try listening to the device again, until it succeeds
+  let mut reconnect_due = None;
+  let rx_device = if virtual_midi {
+    input_port.listen_virtual();
+    None
+  } else {
+    if !input_port.listen(&midi_in_device_name) {
+      warn!("Unable to listen to the midi device {}, trying again", midi_in_device_name);
+      reconnect_due = Some(Instant::now() + RECONNECT_INTERVAL);
     }
-    if is_on {
-      if note_id == launchpad.end_session_note() {
-        midi::actions::end_session(&tx_midi);
-        break;
-      }
-      if note_id == launchpad.stop_note() {
-        midi::actions::stop_note(&mut sinks);
-        continue;
-      }
-      if note_id == launchpad.first_page_note() {
-        referential.first_page();
+    Some(midi::watch_midi_input_device(midi_in_device_name.clone()))
+  };
 
-        midi::refresh_grid(&launchpad, &config, &mut referential, &tx_midi, false);
-        continue;
-      }
-      if note_id == launchpad.last_page_note() {
-        referential.last_page();
+  // Follow the midi clock of another device if configured, the internal tempo otherwise
+  let clock_device = config.get_quantize().and_then(|quantize| quantize.clock_device.clone());
+  let midi_clock = clock_device.as_ref().map(|_| Arc::new(MidiClock::new()));
+  let _conn_clock = clock_device.zip(midi_clock.clone())
+    .and_then(|(name, midi_clock)| midi::listen_midi_clock(&name, midi_clock));
 
-        midi::refresh_grid(&launchpad, &config, &mut referential, &tx_midi, false);
-        continue;
-      }
-      if note_id == launchpad.prev_page_note() {
-        referential.previous_page();
+  let mut engine = Engine::new(config, launchpad.clone(), referential, Box::new(output_bus), Box::new(virtual_bus), tx_midi.clone(), midi_clock);
+  engine.start();
 
-        midi::refresh_grid(&launchpad, &config, &mut referential, &tx_midi, false);
-        continue;
-      }
-      if note_id == launchpad.next_page_note() {
-        referential.next_page();
+  // Stop the main loop on Ctrl+C or when the process is terminated
+  let running = Arc::new(AtomicBool::new(true));
+  let signal_running = running.clone();
+  ctrlc::set_handler(move || signal_running.store(false, Ordering::Relaxed))?;
 
-        midi::refresh_grid(&launchpad, &config, &mut referential, &tx_midi, false);
-        continue;
-      }
-      if note_id == launchpad.swap_hold_mode_note() {
-        config.swap_hold_to_play();
+  // Main loop to receive the midi events, also block the main thread from exiting.
+  while running.load(Ordering::Relaxed) {
+    // Launch the quantized pads and step the fades on time, the loop wakes up for them
+    let timeout = engine.update(Instant::now());
+
+    // Messages that aren't about a pad or a button, such as SysEx or clock, are ignored
+    let event = input_port.receive(timeout).and_then(|message| launchpad.input_format().parse(&message));
+    if let Some(event) = event && !engine.handle(event) {
+      break;
+    }
 
-        midi::refresh_grid(&launchpad, &config, &mut referential, &tx_midi, true);
-        continue;
+    if let Some(rx_device) = &rx_device && let Ok(connected) = rx_device.try_recv() {
+      if connected {
+        info!("Midi device {} is back, reconnecting", midi_in_device_name);
+        reconnect_due = Some(Instant::now());
+      } else {
+        warn!("Midi device {} has been unplugged, waiting for it to come back", midi_in_device_name);
+        reconnect_due = None;
+        input_port.close_input();
       }
-      let bookmark_notes = launchpad.bookmark_notes();
-      if bookmark_notes.contains(&note_id) {
-        let index = bookmark_notes.iter().position(|&r| r == note_id).unwrap();
-        if !config.bookmark_exists(index) {
-          continue;
-        }
-        referential.set_current_bookmark(bookmark_notes[index]);
-        // Get the bookmark parameter from Config based on index
-        let bookmark_path = config.get_bookmark(index).expect("No path found for bookmark");
-        referential.init(bookmark_path);
-
-        midi::refresh_grid(&launchpad, &config, &mut referential, &tx_midi, true);
-        continue;
-      }
-      if let Some(note) = &referential.get_note(note_id) {
-        // Clone to ensure the value won't be freed until it's no longer used.
-        // As without it,
-        // the note would be tied with the temporary value from &referential.get_note(note_id)
-        let note = note.clone();
-        match config.get_hold_to_mode() {
-          HoldMode::Normal | HoldMode::Stop => play_sound(&mut config, &output_handle, &virtual_handle, &tx_midi, &mut sinks, note),
-          HoldMode::Pause => {
-            if let Some((audio_sink, virtual_sink)) = sinks.get(&note.note_id) {
-              if !audio_sink.empty() {
-                audio_sink.play();
-                virtual_sink.play();
-                continue;
-              }
-            }
-            play_sound(&mut config, &output_handle, &virtual_handle, &tx_midi, &mut sinks, note);
-          }
-        }
+    }
+    // The port of a replugged device may not be ready right away
+    if let Some(due) = reconnect_due && due <= Instant::now() {
+      if input_port.listen(&midi_in_device_name) {
+        reconnect_due = None;
+        engine.device_reconnected();
+      } else {
+        reconnect_due = Some(Instant::now() + RECONNECT_INTERVAL);
       }
     }
+    engine.tick(Instant::now());
   }
 
-  Ok(())
-}
-
-fn play_sound(config: &mut Config, output_handle: &OutputStream, virtual_handle: &OutputStream, tx_midi: &Sender<MidiOutputEvent>, sinks: &mut HashMap<u8, (Sink, Sink)>, note: Note) {
-  let (audio_sink, duration) = audio::play_sound(&output_handle, note.path, VOICE_VOLUME);
-  let (virtual_sink, _duration) = audio::play_sound(&virtual_handle, note.path, LOOPBACK_VOLUME);
-
-  let thread_tx_midi = tx_midi.clone();
-  thread_tx_midi.send((note.note_id, note.color, true)).unwrap();
-
-  if config.get_hold_to_mode() == &HoldMode::Normal && let Some(duration) = duration {
-    // Light on the note and light off after the duration
-    thread::spawn(move || {
-      thread::sleep(duration);
-      thread_tx_midi.send((note.note_id, note.color, false)).unwrap();
-    });
+  // Give the launchpad back in the state it was found
+  engine.end_session();
+  tx_midi.send(LedCommand::Shutdown).unwrap();
+  if led_thread.join().is_err() {
+    error!("The LED thread panicked");
   }
 
-  sinks.insert(note.note_id, (audio_sink, virtual_sink));
-}
\ No newline at end of file
+  Ok(())
+}
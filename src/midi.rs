@@ -1,69 +1,225 @@
-use std::sync::mpsc::Sender;
-use log::warn;
-use midir::{MidiInputConnection, MidiInputPort};
-use crate::{MidiOutputEvent, NoteEvent};
+use std::error::Error;
+use std::sync::{Arc, mpsc};
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+use log::{debug, warn};
+use midir::{MidiInputConnection, MidiOutputConnection};
 use crate::config::{Config, HoldMode};
 use crate::launchpad::Launchpad;
+use crate::led::LedCommand;
+use crate::quantize::MidiClock;
 use crate::referential::{Note, Referential};
 
-pub fn get_midi_input_devices() -> Vec<String> {
-    let midi_in = midir::MidiInput::new("launchpad-soundpad").unwrap();
-    let mut devices = vec![];
-    for port in midi_in.ports() {
-        let port_name = midi_in.port_name(&port).unwrap();
-        devices.push(port_name);
-    }
-    devices
+const TEXT_SCROLL_SPEED: u8 = 12;
+const VIRTUAL_PORT_NAME: &str = "launchpad-soundpad";
+// Universal Device Inquiry, sent to every device on the port
+const DEVICE_INQUIRY: [u8; 6] = [240, 126, 127, 6, 1, 247];
+const DEVICE_INQUIRY_TIMEOUT: Duration = Duration::from_millis(500);
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Identity of a device, as answered to the Universal Device Inquiry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceIdentity {
+    pub manufacturer: Vec<u8>,
+    pub family: [u8; 2],
+    pub model: [u8; 2],
+    pub firmware: [u8; 4],
 }
 
-pub fn get_midi_output_devices() -> Vec<String> {
-    let midi_out = midir::MidiOutput::new("launchpad-soundpad").unwrap();
-    let mut devices = vec![];
-    for port in midi_out.ports() {
-        let port_name = midi_out.port_name(&port).unwrap();
-        devices.push(port_name);
+impl DeviceIdentity {
+    /// Parse the Device Inquiry reply: F0 7E <device> 06 02 <manufacturer> <family> <model> <firmware> F7
+    pub fn parse(message: &[u8]) -> Option<DeviceIdentity> {
+        if message.len() < 5 || message[0] != 240 || message[1] != 126 || message[3] != 6 || message[4] != 2 {
+            return None;
+        }
+        // Manufacturer ids starting with 0 are extended to 3 bytes
+        let manufacturer_len = if *message.get(5)? == 0 { 3 } else { 1 };
+        let body = &message[5..];
+        if body.len() < manufacturer_len + 8 {
+            return None;
+        }
+        let (manufacturer, rest) = body.split_at(manufacturer_len);
+        Some(DeviceIdentity {
+            manufacturer: manufacturer.to_vec(),
+            family: [rest[0], rest[1]],
+            model: [rest[2], rest[3]],
+            firmware: [rest[4], rest[5], rest[6], rest[7]],
+        })
     }
-    devices
 }
 
-pub fn select_midi_output_device(name: &str) -> Option<midir::MidiOutputConnection> {
-    let midi_out = midir::MidiOutput::new("launchpad-soundpad").unwrap();
-    for port in midi_out.ports() {
-        let port_name = midi_out.port_name(&port).unwrap();
-        if port_name.eq(name) {
-            return midi_out.connect(&port, "launchpad-soundpad-output").ok();
+/// Midi port of the controller, the midir connections in production.
+pub trait MidiPort: Send {
+    fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>>;
+    /// Next message received from the device, None if nothing came before the timeout.
+    fn receive(&mut self, timeout: Duration) -> Option<Vec<u8>>;
+}
+
+/// Midi port through midir, the input and the output are opened separately as their names differ.
+pub struct MidirPort {
+    input: Option<MidiInputConnection<()>>,
+    output: Option<MidiOutputConnection>,
+    tx_input: Sender<Vec<u8>>,
+    rx_input: Receiver<Vec<u8>>,
+}
+
+impl MidirPort {
+    pub fn new(output: Option<MidiOutputConnection>) -> Self {
+        let (tx_input, rx_input) = mpsc::channel();
+        MidirPort {
+            input: None,
+            output,
+            tx_input,
+            rx_input,
         }
     }
-    None
+
+    /// Listen to the input port, returns false if it isn't found.
+    pub fn listen(&mut self, name: &str) -> bool {
+        let Ok(midi_in) = midir::MidiInput::new("launchpad-soundpad") else {
+            return false;
+        };
+        let found_port = midi_in.ports().into_iter().find(|port| midi_in.port_name(port).is_ok_and(|port_name| port_name.eq(name)));
+        self.input = found_port.and_then(|port| {
+            midi_in.connect(&port, "launchpad-soundpad-input", input_callback(self.tx_input.clone()), ()).ok()
+        });
+        self.input.is_some()
+    }
+
+    /// Create a virtual input port that other software can send the pad events to.
+    #[cfg(unix)]
+    pub fn listen_virtual(&mut self) -> bool {
+        use midir::os::unix::VirtualInput;
+        let midi_in = midir::MidiInput::new("launchpad-soundpad").unwrap();
+        self.input = midi_in.create_virtual(VIRTUAL_PORT_NAME, input_callback(self.tx_input.clone()), ())
+            .map_err(|error| warn!("Unable to create the virtual midi input: {}", error))
+            .ok();
+        self.input.is_some()
+    }
+
+    #[cfg(not(unix))]
+    pub fn listen_virtual(&mut self) -> bool {
+        warn!("Virtual midi ports are not supported on this platform");
+        false
+    }
+
+    /// Stop listening, such as when the device has been unplugged.
+    pub fn close_input(&mut self) {
+        self.input = None;
+    }
 }
 
-pub fn listen_midi_input(name: &str, tx_on: Sender<NoteEvent>) -> Option<MidiInputConnection<()>> {
-    let midi_in = midir::MidiInput::new("launchpad-soundpad").unwrap();
-    let mut found_port: Option<MidiInputPort> = None;
-    for port in midi_in.ports() {
-        let port_name = midi_in.port_name(&port).unwrap();
-        if port_name.eq(name) {
-            found_port = Some(port);
-            break;
+impl MidiPort for MidirPort {
+    fn send(&mut self, message: &[u8]) -> Result<(), Box<dyn Error>> {
+        let output = self.output.as_mut().ok_or("No midi output port")?;
+        output.send(message)?;
+        Ok(())
+    }
+
+    fn receive(&mut self, timeout: Duration) -> Option<Vec<u8>> {
+        self.rx_input.recv_timeout(timeout).ok()
+    }
+}
+
+fn input_callback(tx_input: Sender<Vec<u8>>) -> impl FnMut(u64, &[u8], &mut ()) + Send + 'static {
+    move |_stamp: u64, message: &[u8], _| {
+        if let Err(error) = tx_input.send(message.to_vec()) {
+            warn!("Error sending midi message: {}", error);
         }
     }
-    if let Some(port) = found_port {
-        let conn = midi_in.connect(&port, "launchpad-soundpad-input", move |_stamp: u64, message: &[u8], _| {
-            let tx_on = tx_on.clone();
-            let is_on = message[2] == 127;
-            if let Err(error) = tx_on.send((message[1], is_on)) {
-                warn!("Error sending midi message: {}", error);
+}
+
+pub fn get_midi_input_devices() -> Vec<String> {
+    // Called while the devices come and go, a port may be gone before its name is read
+    let Ok(midi_in) = midir::MidiInput::new("launchpad-soundpad") else {
+        return vec![];
+    };
+    midi_in.ports().iter().filter_map(|port| midi_in.port_name(port).ok()).collect()
+}
+
+pub fn get_midi_output_devices() -> Vec<String> {
+    let Ok(midi_out) = midir::MidiOutput::new("launchpad-soundpad") else {
+        return vec![];
+    };
+    midi_out.ports().iter().filter_map(|port| midi_out.port_name(port).ok()).collect()
+}
+
+/// Poll the midi input devices and notify when the device is unplugged (false) or plugged back (true).
+pub fn watch_midi_input_device(name: String) -> Receiver<bool> {
+    let (tx_device, rx_device) = mpsc::channel();
+    thread::spawn(move || {
+        let mut connected = true;
+        loop {
+            thread::sleep(DEVICE_POLL_INTERVAL);
+            let present = get_midi_input_devices().contains(&name);
+            if present != connected {
+                connected = present;
+                if tx_device.send(connected).is_err() {
+                    break;
+                }
             }
-        }, ()).ok();
-        return conn;
+        }
+    });
+    rx_device
+}
+
+pub fn select_midi_output_device(name: &str) -> Option<midir::MidiOutputConnection> {
+    let midi_out = midir::MidiOutput::new("launchpad-soundpad").ok()?;
+    let port = midi_out.ports().into_iter().find(|port| midi_out.port_name(port).is_ok_and(|port_name| port_name.eq(name)))?;
+    midi_out.connect(&port, "launchpad-soundpad-output").ok()
+}
+
+/// Send a Universal Device Inquiry on the output and wait for the reply on the input port.
+pub fn identify_device(name: &str, conn_out: &mut MidiOutputConnection) -> Option<DeviceIdentity> {
+    let midi_in = midir::MidiInput::new("launchpad-soundpad").unwrap();
+    let port = midi_in.ports().into_iter().find(|port| midi_in.port_name(port).is_ok_and(|port_name| port_name.eq(name)))?;
+    let (tx_identity, rx_identity) = mpsc::channel();
+    let _conn_in = midi_in.connect(&port, "launchpad-soundpad-inquiry", move |_stamp: u64, message: &[u8], _| {
+        if let Some(identity) = DeviceIdentity::parse(message) {
+            let _ = tx_identity.send(identity);
+        }
+    }, ()).ok()?;
+
+    if let Err(error) = conn_out.send(&DEVICE_INQUIRY) {
+        warn!("Error sending device inquiry: {}", error);
+        return None;
     }
+    let identity = rx_identity.recv_timeout(DEVICE_INQUIRY_TIMEOUT).ok();
+    debug!("Device inquiry reply: {:?}", identity);
+    identity
+}
+
+/// Follow the midi clock sent on the input port.
+pub fn listen_midi_clock(name: &str, clock: Arc<MidiClock>) -> Option<MidiInputConnection<()>> {
+    let midi_in = midir::MidiInput::new("launchpad-soundpad").unwrap();
+    let port = midi_in.ports().into_iter().find(|port| midi_in.port_name(port).is_ok_and(|port_name| port_name.eq(name)))?;
+    midi_in.connect(&port, "launchpad-soundpad-clock", move |_stamp: u64, message: &[u8], _| {
+        clock.handle(message);
+    }, ()).ok()
+}
+
+/// Create a virtual output port where other software can read the LEDs from.
+#[cfg(unix)]
+pub fn create_virtual_output() -> Option<MidiOutputConnection> {
+    use midir::os::unix::VirtualOutput;
+    let midi_out = midir::MidiOutput::new("launchpad-soundpad").unwrap();
+    midi_out.create_virtual(VIRTUAL_PORT_NAME)
+        .map_err(|error| warn!("Unable to create the virtual midi output: {}", error))
+        .ok()
+}
+
+#[cfg(not(unix))]
+pub fn create_virtual_output() -> Option<MidiOutputConnection> {
+    warn!("Virtual midi ports are not supported on this platform");
     None
 }
 
-pub fn refresh_grid(launchpad: &Launchpad, config: &Config, referential: &mut Referential, tx_midi: &Sender<MidiOutputEvent>, with_header: bool) {
+pub fn refresh_grid(launchpad: &Launchpad, config: &Config, referential: &mut Referential, tx_midi: &Sender<LedCommand>, with_header: bool) {
     let thread_tx_midi = tx_midi.clone();
+    let palette = launchpad.palette();
     // Clear the grid and right side
-    clear_grid(&thread_tx_midi, if with_header { 99 } else { 89 });
+    clear_grid(launchpad, &thread_tx_midi, with_header);
 
     for note in referential.current_page().get_notes().iter() {
         thread_tx_midi.send(note.into()).unwrap();
@@ -71,61 +227,113 @@ pub fn refresh_grid(launchpad: &Launchpad, config: &Config, referential: &mut Re
 
     if with_header {
         if referential.get_nb_pages() > 1 {
-            thread_tx_midi.send(Note::white(launchpad.first_page_note()).into()).unwrap();
-            thread_tx_midi.send(Note::white(launchpad.last_page_note()).into()).unwrap();
-            thread_tx_midi.send(Note::white(launchpad.prev_page_note()).into()).unwrap();
-            thread_tx_midi.send(Note::white(launchpad.next_page_note()).into()).unwrap();
-        }
-        thread_tx_midi.send(Note::white(launchpad.end_session_note()).into()).unwrap();
-        match config.get_hold_to_mode() {
-            HoldMode::Normal => {
-                thread_tx_midi.send(Note::white(launchpad.swap_hold_mode_note()).into()).unwrap();
-            }
-            HoldMode::Pause => {
-                thread_tx_midi.send(Note::yellow(launchpad.swap_hold_mode_note()).into()).unwrap();
-            }
-            HoldMode::Stop => {
-                thread_tx_midi.send(Note::red(launchpad.swap_hold_mode_note()).into()).unwrap();
-            }
+            thread_tx_midi.send(palette.white(launchpad.first_page_note()).into()).unwrap();
+            thread_tx_midi.send(palette.white(launchpad.last_page_note()).into()).unwrap();
+            thread_tx_midi.send(palette.white(launchpad.prev_page_note()).into()).unwrap();
+            thread_tx_midi.send(palette.white(launchpad.next_page_note()).into()).unwrap();
         }
+        thread_tx_midi.send(palette.white(launchpad.end_session_note()).into()).unwrap();
     }
 
-    thread_tx_midi.send(Note::white(launchpad.stop_note()).into()).unwrap();
+    // Flash between the two modes when the page has notes with their own mode
+    let modes = referential.current_page().get_hold_modes(*config.get_hold_to_mode());
+    let color = hold_mode_color(launchpad, modes[0]);
+    let command = match modes.get(1) {
+        Some(other) => LedCommand::flash(launchpad.swap_hold_mode_note(), color, hold_mode_color(launchpad, *other)),
+        None => LedCommand::static_color(launchpad.swap_hold_mode_note(), color),
+    };
+    thread_tx_midi.send(command).unwrap();
+
+    thread_tx_midi.send(palette.white(launchpad.stop_note()).into()).unwrap();
+
+    let current_page = referential.get_current_page_index();
+    for (index, page_note) in launchpad.page_notes().iter().enumerate() {
+        if index == current_page {
+            thread_tx_midi.send(palette.green(*page_note).into()).unwrap();
+        } else if index < referential.get_nb_pages() as usize {
+            thread_tx_midi.send(palette.white(*page_note).into()).unwrap();
+        }
+    }
 
     for (i, bookmark_note) in launchpad.bookmark_notes().iter().enumerate() {
         if !config.bookmark_exists(i) {
             continue;
         }
         if referential.is_current_bookmark(*bookmark_note) {
-            thread_tx_midi.send(Note::green(*bookmark_note).into()).unwrap();
+            thread_tx_midi.send(palette.green(*bookmark_note).into()).unwrap();
         } else {
-            thread_tx_midi.send(Note::white(*bookmark_note).into()).unwrap();
+            thread_tx_midi.send(palette.white(*bookmark_note).into()).unwrap();
         }
     }
 }
 
-pub fn clear_grid(thread_tx_midi: &Sender<MidiOutputEvent>, max_note: u8) {
-    for note in 1..max_note {
+fn hold_mode_color(launchpad: &Launchpad, mode: HoldMode) -> u8 {
+    let palette = launchpad.palette();
+    match mode {
+        HoldMode::Normal => palette.white,
+        HoldMode::Pause => palette.yellow,
+        HoldMode::Stop => palette.red,
+        HoldMode::Toggle => palette.green,
+    }
+}
+
+/// Light one pad of the grid per page, the current page being highlighted.
+pub fn show_page_overlay(launchpad: &Launchpad, referential: &Referential, tx_midi: &Sender<LedCommand>) {
+    let palette = launchpad.palette();
+    let current_page = referential.get_current_page_index();
+    for (index, note_id) in launchpad.grid_notes().into_iter().enumerate() {
+        let note = if index == current_page {
+            palette.green(note_id)
+        } else if index < referential.get_nb_pages() as usize {
+            palette.white(note_id)
+        } else {
+            Note::off(note_id)
+        };
+        tx_midi.send(note.into()).unwrap();
+    }
+}
+
+/// Light the status pad of an audio device in red while it is unavailable.
+pub fn show_bus_status(status_note: Option<u8>, available: bool, launchpad: &Launchpad, tx_midi: &Sender<LedCommand>) {
+    if let Some(status_note) = status_note {
+        let note = if available { Note::off(status_note) } else { launchpad.palette().red(status_note) };
+        tx_midi.send(note.into()).unwrap();
+    }
+}
+
+pub fn scroll_text(launchpad: &Launchpad, config: &Config, tx_midi: &Sender<LedCommand>, text: &str) {
+    if !config.scroll_text_enabled() {
+        return;
+    }
+    if let Some(command) = launchpad.text_scroll_command(text, launchpad.palette().white, TEXT_SCROLL_SPEED) {
+        tx_midi.send(LedCommand::SysEx(command)).unwrap();
+    }
+}
+
+pub fn clear_grid(launchpad: &Launchpad, thread_tx_midi: &Sender<LedCommand>, with_header: bool) {
+    for note in launchpad.lit_notes(with_header) {
         thread_tx_midi.send(Note::off(note).into()).unwrap();
     }
 }
 
 pub mod actions {
-    use std::collections::HashMap;
     use std::sync::mpsc::Sender;
-    use std::thread;
-    use std::time::Duration;
-    use rodio::Sink;
+    use crate::audio::Sinks;
     use crate::midi::clear_grid;
-    use crate::MidiOutputEvent;
+    use crate::launchpad::Launchpad;
+    use crate::led::LedCommand;
 
-    pub fn end_session(tx_midi: &Sender<MidiOutputEvent>) {
-        clear_grid(&tx_midi, 99);
-        thread::sleep(Duration::from_millis(100));
+    /// Stop the sounds, turn off the LEDs and leave the programmer mode.
+    pub fn end_session(launchpad: &Launchpad, tx_midi: &Sender<LedCommand>, sinks: &mut Sinks) {
+        stop_note(sinks);
+        clear_grid(launchpad, tx_midi, true);
+        if let Some(command) = launchpad.live_mode_command() {
+            tx_midi.send(LedCommand::SysEx(command.to_vec())).unwrap();
+        }
     }
 
-    pub fn stop_note(sinks: &mut HashMap<u8, (Sink, Sink)>) {
-        for (audio_sink, virtual_sink) in sinks.values() {
+    pub fn stop_note(sinks: &mut Sinks) {
+        for (audio_sink, virtual_sink) in sinks.values().flatten() {
             audio_sink.stop();
             virtual_sink.stop();
         }
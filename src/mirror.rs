@@ -0,0 +1,69 @@
+use log::{debug, warn};
+use midir::MidiOutputConnection;
+
+use crate::config::{MirrorConfig, MirrorMessage};
+use crate::midi;
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+const PROGRAM_CHANGE: u8 = 0xC0;
+
+/// Second midi output notified of the soundboard events, for DAWs or lighting controllers.
+pub struct Mirror {
+  connection: Option<MidiOutputConnection>,
+  config: Option<MirrorConfig>,
+}
+
+impl Mirror {
+  pub fn open(config: Option<&MirrorConfig>) -> Mirror {
+    let connection = config.and_then(|config| {
+      let connection = midi::select_midi_output_device(&config.device);
+      if connection.is_none() {
+        warn!("Mirror midi output {} not found", config.device);
+      }
+      connection
+    });
+    Mirror {
+      connection,
+      config: config.cloned(),
+    }
+  }
+
+  pub fn pad_played(&mut self, note_id: u8) {
+    if let Some(config) = &self.config {
+      self.send(config.pad_played, note_id, true);
+    }
+  }
+
+  pub fn pad_stopped(&mut self, note_id: u8) {
+    if let Some(config) = &self.config {
+      self.send(config.pad_stopped, note_id, false);
+    }
+  }
+
+  pub fn page_changed(&mut self, page_index: usize) {
+    if let Some(config) = &self.config {
+      self.send(config.page_changed, page_index.min(127) as u8, true);
+    }
+  }
+
+  fn send(&mut self, kind: MirrorMessage, number: u8, is_on: bool) {
+    let (Some(connection), Some(config)) = (self.connection.as_mut(), &self.config) else {
+      return;
+    };
+    let channel = config.channel & 0x0F;
+    let message = match (kind, is_on) {
+      (MirrorMessage::Note, true) => vec![NOTE_ON | channel, number, 127],
+      (MirrorMessage::Note, false) => vec![NOTE_OFF | channel, number, 0],
+      (MirrorMessage::ControlChange, true) => vec![CONTROL_CHANGE | channel, number, 127],
+      (MirrorMessage::ControlChange, false) => vec![CONTROL_CHANGE | channel, number, 0],
+      (MirrorMessage::ProgramChange, _) => vec![PROGRAM_CHANGE | channel, number],
+      (MirrorMessage::Nothing, _) => return,
+    };
+    debug!("Mirroring {:?}", message);
+    if let Err(error) = connection.send(&message) {
+      warn!("Error sending mirror midi message: {}", error);
+    }
+  }
+}
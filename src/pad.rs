@@ -0,0 +1,136 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::config::HoldMode;
+use crate::referential::Note;
+
+// Voices of a pad stacking by default, the oldest one is stopped beyond
+const DEFAULT_STACKED_VOICES: usize = 8;
+
+/// What a pad is doing, the LEDs of the playing pads are driven from it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PadState {
+  Idle,
+  /// Waiting for the next beat or bar to launch.
+  Queued,
+  Playing,
+  /// Resumed by the next press.
+  Paused,
+  /// Asked to stop, forgotten on the next tick once its fade out is over.
+  Stopping,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PadEvent {
+  Press,
+  Release,
+  StopAll,
+  /// The pads keep their sound across pages, a release applies to the sound the pad launched.
+  PageChange,
+  /// A paused sound can only be resumed in the Pause mode.
+  ModeChange,
+  /// The quantized launch is due.
+  Launched,
+  /// Another pad of the same choke group started.
+  Choked,
+  /// Another music pad started, the pad fades out to let it in over the crossfade of the other pad.
+  CrossFaded(Duration),
+  /// The sound reached its end, or the stopping sound is over.
+  Ended,
+}
+
+/// What pressing a pad does while it is playing, in the Normal mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Retrigger {
+  /// Stop the sound and play it again from the beginning.
+  Restart,
+  /// Play another copy over the previous ones, up to this number of copies.
+  Stack(usize),
+  /// Keep playing, the press is ignored.
+  Ignore,
+  /// Stop the sound.
+  Toggle,
+}
+
+impl Default for Retrigger {
+  fn default() -> Self {
+    Retrigger::Stack(DEFAULT_STACKED_VOICES)
+  }
+}
+
+impl FromStr for Retrigger {
+  type Err = String;
+
+  /// Restart, Stack, Stack:<copies>, Ignore or Toggle.
+  fn from_str(retrigger: &str) -> Result<Self, Self::Err> {
+    match retrigger.trim().split_once(':') {
+      Some(("Stack", copies)) => copies.trim().parse()
+        .map(|copies: usize| Retrigger::Stack(copies.max(1)))
+        .map_err(|_| format!("Invalid number of copies {}", copies)),
+      Some(_) => Err(format!("Unknown retrigger policy {}", retrigger)),
+      None => match retrigger.trim() {
+        "Restart" => Ok(Retrigger::Restart),
+        "Stack" => Ok(Retrigger::default()),
+        "Ignore" => Ok(Retrigger::Ignore),
+        "Toggle" => Ok(Retrigger::Toggle),
+        _ => Err(format!("Unknown retrigger policy {}", retrigger)),
+      },
+    }
+  }
+}
+
+impl PadState {
+  /// State after the event in the hold mode of the pad, None if the event doesn't apply to the pad.
+  /// A Playing state reached from Playing means the sound is played again, as the retrigger policy says.
+  pub fn next(self, event: PadEvent, mode: HoldMode, retrigger: Retrigger, quantized: bool) -> Option<PadState> {
+    let start = if quantized { PadState::Queued } else { PadState::Playing };
+    match (self, event) {
+      (PadState::Idle | PadState::Stopping, PadEvent::Press) => Some(start),
+      (PadState::Queued, PadEvent::Launched) => Some(PadState::Playing),
+      (PadState::Queued, PadEvent::Release) if mode == HoldMode::Stop => Some(PadState::Idle),
+      (PadState::Queued, PadEvent::StopAll) => Some(PadState::Idle),
+      (PadState::Playing, PadEvent::Press) => match (mode, retrigger) {
+        (HoldMode::Toggle, _) | (HoldMode::Normal, Retrigger::Toggle) => Some(PadState::Stopping),
+        (HoldMode::Normal, Retrigger::Ignore) => None,
+        (HoldMode::Normal, _) => Some(start),
+        _ => None,
+      },
+      (PadState::Playing, PadEvent::Release) => match mode {
+        HoldMode::Normal | HoldMode::Toggle => None,
+        HoldMode::Stop => Some(PadState::Stopping),
+        HoldMode::Pause => Some(PadState::Paused),
+      },
+      (PadState::Queued, PadEvent::Choked | PadEvent::CrossFaded(_)) => Some(PadState::Idle),
+      (PadState::Playing | PadState::Paused, PadEvent::StopAll | PadEvent::Choked | PadEvent::CrossFaded(_)) => Some(PadState::Stopping),
+      // Cut the fade out
+      (PadState::Stopping, PadEvent::StopAll) => Some(PadState::Stopping),
+      (PadState::Playing, PadEvent::Ended) => Some(PadState::Idle),
+      (PadState::Paused, PadEvent::Press) => Some(PadState::Playing),
+      (PadState::Paused, PadEvent::ModeChange) if mode != HoldMode::Pause => Some(PadState::Stopping),
+      (PadState::Stopping, PadEvent::Ended) => Some(PadState::Idle),
+      _ => None,
+    }
+  }
+}
+
+/// A pad that isn't idle, with the note it launched.
+#[derive(Debug, Clone, Copy)]
+pub struct Pad {
+  pub state: PadState,
+  pub note: Note,
+  // The progress of the sound is displayed on the pad instead of its state
+  pub shows_progress: bool,
+  // Length of the crossfade with the other music pads, None if the pad isn't one
+  pub crossfade: Option<Duration>,
+}
+
+impl Pad {
+  pub fn new(note: Note) -> Self {
+    Pad {
+      state: PadState::Idle,
+      note,
+      shows_progress: false,
+      crossfade: None,
+    }
+  }
+}
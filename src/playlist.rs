@@ -0,0 +1,135 @@
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+
+use crate::variation;
+
+/// Tracks a music pad plays back to back.
+#[derive(Debug)]
+pub struct Playlist {
+  tracks: Vec<String>,
+}
+
+impl Playlist {
+  /// Tracks of a folder sorted by name, or of an M3U file in its order.
+  pub fn load(path: &str) -> Result<Playlist, String> {
+    let tracks = if Path::new(path).is_dir() {
+      variation::list_folder(path)?
+    } else {
+      read_m3u(path)?
+    };
+    if tracks.is_empty() {
+      return Err(format!("No track found in {}", path));
+    }
+    Ok(Playlist { tracks })
+  }
+
+  pub fn track(&self, index: usize) -> &str {
+    &self.tracks[index]
+  }
+
+  pub fn len(&self) -> usize {
+    self.tracks.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.tracks.is_empty()
+  }
+}
+
+/// The paths of the M3U file, relative to its folder unless they are absolute.
+fn read_m3u(path: &str) -> Result<Vec<String>, String> {
+  let content = fs::read_to_string(path).map_err(|error| format!("Unable to read {}: {}", path, error))?;
+  let folder = Path::new(path).parent().unwrap_or(Path::new(""));
+  Ok(content.lines()
+    .map(str::trim)
+    // The extended M3U information is ignored
+    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    .map(|track| folder.join(track).to_string_lossy().into_owned())
+    .collect())
+}
+
+/// Where a playing pad is in its playlist.
+#[derive(Debug)]
+pub struct Queue {
+  // Indexes of the tracks in their playing order
+  order: Vec<usize>,
+  position: usize,
+  // The next track has been appended after the current one, with its duration if known
+  next_appended: Option<Option<Duration>>,
+  last_press: Option<Instant>,
+}
+
+impl Queue {
+  pub fn new(playlist: &Playlist, shuffle: bool) -> Self {
+    let mut order: Vec<usize> = (0..playlist.len()).collect();
+    if shuffle {
+      order.shuffle(&mut rand::rng());
+    }
+    Queue {
+      order,
+      position: 0,
+      next_appended: None,
+      last_press: None,
+    }
+  }
+
+  /// Index of the track playing.
+  pub fn current(&self) -> usize {
+    self.order[self.position]
+  }
+
+  /// Index of the track after the one playing, None at the end of the playlist.
+  pub fn next(&self) -> Option<usize> {
+    self.order.get(self.position + 1).copied()
+  }
+
+  pub fn is_next_appended(&self) -> bool {
+    self.next_appended.is_some()
+  }
+
+  pub fn set_next_appended(&mut self, duration: Option<Duration>) {
+    self.next_appended = Some(duration);
+  }
+
+  /// The appended track started, returns its duration if known.
+  pub fn advance(&mut self) -> Option<Duration> {
+    self.position += 1;
+    self.next_appended.take().flatten()
+  }
+
+  /// Move to the next or previous track, returns false at the ends of the playlist.
+  pub fn skip(&mut self, forward: bool) -> bool {
+    let position = if forward { self.position + 1 } else { self.position.wrapping_sub(1) };
+    if position >= self.order.len() {
+      return false;
+    }
+    self.position = position;
+    self.next_appended = None;
+    true
+  }
+
+  /// Record a press on the pad, returns true if it follows the previous one close enough to be a double press.
+  pub fn press(&mut self, now: Instant, double_press: Duration) -> bool {
+    let double = self.last_press.is_some_and(|last_press| now.duration_since(last_press) < double_press);
+    // A third press starts another double press
+    self.last_press = if double { None } else { Some(now) };
+    double
+  }
+
+  /// When the last press turns into a single press, unless another press comes before.
+  pub fn single_press_due(&self, double_press: Duration) -> Option<Instant> {
+    self.last_press.map(|last_press| last_press + double_press)
+  }
+
+  /// Returns true once if the last press wasn't followed by another one in time, it is then a single press.
+  pub fn single_press_over(&mut self, now: Instant, double_press: Duration) -> bool {
+    if self.single_press_due(double_press).is_some_and(|due| due <= now) {
+      self.last_press = None;
+      return true;
+    }
+    false
+  }
+}
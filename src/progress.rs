@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use crate::audio::{Playback, Sinks, Voice};
+use crate::config::Config;
+use crate::launchpad::Launchpad;
+use crate::led::LedCommand;
+use crate::referential::{Note, Referential};
+
+// Only sounds longer than this duration are displayed on the progress strip.
+const LONG_SOUND_DURATION: Duration = Duration::from_secs(10);
+
+/// Keeps track of the playing sounds to display their progress on the launchpad.
+pub struct Progress {
+  durations: HashMap<u8, Duration>,
+  strip_note: Option<u8>,
+}
+
+impl Default for Progress {
+  fn default() -> Self {
+    Progress::new()
+  }
+}
+
+impl Progress {
+  pub fn new() -> Self {
+    Progress {
+      durations: HashMap::new(),
+      strip_note: None,
+    }
+  }
+
+  /// Register a sound that just started, returns true if its progress will be displayed on its pad.
+  pub fn track(&mut self, config: &Config, note_id: u8, duration: Option<Duration>) -> bool {
+    let Some(duration) = duration else {
+      self.durations.remove(&note_id);
+      return false;
+    };
+    self.durations.insert(note_id, duration);
+    if duration >= LONG_SOUND_DURATION {
+      self.strip_note = Some(note_id);
+    }
+    config.show_playback_progress()
+  }
+
+  /// Update the LEDs of the playing pads and of the progress strip.
+  pub fn refresh(&mut self, launchpad: &Launchpad, config: &Config, referential: &Referential, sinks: &Sinks, tx_midi: &Sender<LedCommand>) {
+    if config.show_playback_progress() {
+      let mut ended = vec![];
+      for (note_id, duration) in self.durations.iter() {
+        match sinks.get(note_id).and_then(|voices| latest_sink(voices)) {
+          Some(sink) => {
+            if !sink.is_paused() {
+              let color = progress_color(launchpad, ratio(sink, *duration));
+              tx_midi.send(LedCommand::static_color(*note_id, color)).unwrap();
+            }
+          }
+          _ => ended.push(*note_id),
+        }
+      }
+      for note_id in ended {
+        self.durations.remove(&note_id);
+        tx_midi.send(current_led(referential, note_id)).unwrap();
+      }
+    }
+
+    if let Some(note_id) = self.strip_note {
+      let strip_notes = launchpad.progress_strip_notes();
+      let playing = sinks.get(&note_id).and_then(|voices| latest_sink(voices));
+      match (config.show_progress_strip(), playing, self.durations.get(&note_id)) {
+        (true, Some(sink), Some(duration)) => {
+          let color = referential.get_note(note_id).map_or(launchpad.palette().white, |note| note.color);
+          let lit = (ratio(sink, *duration) * strip_notes.len() as f32).ceil() as usize;
+          for (i, strip_note) in strip_notes.iter().enumerate() {
+            let color = if i < lit { color } else { 0 };
+            tx_midi.send(LedCommand::static_color(*strip_note, color)).unwrap();
+          }
+        }
+        _ => {
+          // Give the bottom row back to the page
+          self.strip_note = None;
+          for strip_note in strip_notes {
+            tx_midi.send(current_led(referential, *strip_note)).unwrap();
+          }
+        }
+      }
+    }
+  }
+}
+
+/// The sink of the most recent sound still playing, on the first available audio device.
+fn latest_sink(voices: &[Voice]) -> Option<&dyn Playback> {
+  voices.iter().rev()
+    .flat_map(|(audio_sink, virtual_sink)| [audio_sink.as_ref(), virtual_sink.as_ref()])
+    .find(|sink| !sink.empty())
+}
+
+fn ratio(sink: &dyn Playback, duration: Duration) -> f32 {
+  if duration.is_zero() {
+    return 1.0;
+  }
+  (sink.position().as_secs_f32() / duration.as_secs_f32()).clamp(0.0, 1.0)
+}
+
+/// Color of the progress, from the start to the end of the palette progress colors.
+pub fn progress_color(launchpad: &Launchpad, ratio: f32) -> u8 {
+  let colors = &launchpad.palette().progress;
+  if colors.is_empty() {
+    return launchpad.palette().white;
+  }
+  let index = (ratio * (colors.len() - 1) as f32).round() as usize;
+  colors[index.min(colors.len() - 1)]
+}
+
+fn current_led(referential: &Referential, note_id: u8) -> LedCommand {
+  referential.get_note(note_id).unwrap_or(Note::off(note_id)).into()
+}
@@ -0,0 +1,182 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::{Division, QuantizeConfig};
+use crate::referential::Note;
+
+// Midi clock messages, the clock is sent 24 times per beat
+const CLOCK_TICK: u8 = 0xF8;
+const CLOCK_START: u8 = 0xFA;
+const CLOCK_CONTINUE: u8 = 0xFB;
+const CLOCK_STOP: u8 = 0xFC;
+const TICKS_PER_BEAT: u32 = 24;
+
+/// Tempo followed from the midi clock messages of another device.
+pub struct MidiClock {
+  state: Mutex<MidiClockState>,
+}
+
+struct MidiClockState {
+  running: bool,
+  ticks: u32,
+  last_tick: Option<Instant>,
+  tick_interval: Option<Duration>,
+}
+
+impl Default for MidiClock {
+  fn default() -> Self {
+    MidiClock::new()
+  }
+}
+
+impl MidiClock {
+  pub fn new() -> Self {
+    MidiClock {
+      state: Mutex::new(MidiClockState {
+        running: false,
+        ticks: 0,
+        last_tick: None,
+        tick_interval: None,
+      }),
+    }
+  }
+
+  pub fn handle(&self, message: &[u8]) {
+    let mut state = self.state.lock().unwrap();
+    match message.first() {
+      Some(&CLOCK_TICK) => {
+        let now = Instant::now();
+        if let Some(last_tick) = state.last_tick {
+          let interval = now - last_tick;
+          // Smooth the jitter of the incoming clock
+          state.tick_interval = Some(match state.tick_interval {
+            Some(previous) => (previous * 7 + interval) / 8,
+            None => interval,
+          });
+        }
+        state.last_tick = Some(now);
+        state.ticks = state.ticks.wrapping_add(1);
+        state.running = true;
+      }
+      Some(&CLOCK_START) => {
+        state.ticks = 0;
+        state.running = true;
+      }
+      Some(&CLOCK_CONTINUE) => state.running = true,
+      Some(&CLOCK_STOP) => state.running = false,
+      _ => {}
+    }
+  }
+
+  /// Time of the next division, None if the clock isn't running.
+  fn next_boundary(&self, ticks_per_division: u32) -> Option<Instant> {
+    let state = self.state.lock().unwrap();
+    if !state.running {
+      return None;
+    }
+    let remaining = ticks_per_division - state.ticks % ticks_per_division;
+    Some(state.last_tick? + state.tick_interval? * remaining)
+  }
+}
+
+/// Quantization asked by a note of the page, the pads are launched right away otherwise.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quantize {
+  /// On the division of the config.
+  On,
+  /// On the next beat or bar, whatever the division of the config.
+  Division(Division),
+  /// Right away, even in a quantized bookmark.
+  Off,
+}
+
+impl FromStr for Quantize {
+  type Err = String;
+
+  /// On, Off, Beat or Bar.
+  fn from_str(quantize: &str) -> Result<Self, Self::Err> {
+    match quantize.trim() {
+      "On" => Ok(Quantize::On),
+      "Off" => Ok(Quantize::Off),
+      "Beat" => Ok(Quantize::Division(Division::Beat)),
+      "Bar" => Ok(Quantize::Division(Division::Bar)),
+      _ => Err(format!("Unknown quantization {}", quantize)),
+    }
+  }
+}
+
+enum Clock {
+  Internal { start: Instant },
+  Midi(Arc<MidiClock>),
+}
+
+/// Delays the pad launches to the next beat or bar, like a clip launcher.
+pub struct Quantizer {
+  // The default one if the config has none, for the notes that opt in
+  config: QuantizeConfig,
+  clock: Clock,
+  queued: Vec<(Instant, Note)>,
+}
+
+impl Quantizer {
+  pub fn new(config: Option<&QuantizeConfig>, midi_clock: Option<Arc<MidiClock>>) -> Self {
+    Quantizer {
+      config: config.cloned().unwrap_or_default(),
+      clock: match midi_clock {
+        Some(midi_clock) => Clock::Midi(midi_clock),
+        None => Clock::Internal { start: Instant::now() },
+      },
+      queued: vec![],
+    }
+  }
+
+  /// Division the note is launched on, its own or the one of the config for the quantized bookmarks.
+  /// None if it is launched right away.
+  pub fn division(&self, note: &Note, bookmark: Option<usize>) -> Option<Division> {
+    let config = &self.config;
+    match note.quantize {
+      Some(Quantize::On) => Some(config.division),
+      Some(Quantize::Division(division)) => Some(division),
+      Some(Quantize::Off) => None,
+      None => bookmark.filter(|number| config.bookmarks.contains(number)).map(|_| config.division),
+    }
+  }
+
+  /// Queue the note until the next division.
+  pub fn queue(&mut self, note: Note, division: Division) {
+    let config = &self.config;
+    let beats = match division {
+      Division::Beat => 1,
+      Division::Bar => config.beats_per_bar.max(1),
+    };
+    let now = Instant::now();
+    let launch = match &self.clock {
+      Clock::Internal { start } => {
+        let period = Duration::from_secs_f32(60.0 / config.tempo.max(1.0)) * beats;
+        let elapsed = now - *start;
+        let divisions = (elapsed.as_secs_f64() / period.as_secs_f64()).floor() as u32 + 1;
+        *start + period * divisions
+      }
+      // Launch right away if the clock isn't running
+      Clock::Midi(midi_clock) => midi_clock.next_boundary(TICKS_PER_BEAT * beats).unwrap_or(now),
+    };
+    self.queued.push((launch, note));
+  }
+
+  pub fn cancel(&mut self, note_id: u8) {
+    self.queued.retain(|(_, note)| note.note_id != note_id);
+  }
+
+  /// Remove and return the notes to launch now.
+  pub fn due(&mut self, now: Instant) -> Vec<Note> {
+    let (due, queued) = self.queued.iter().partition(|(launch, _)| *launch <= now);
+    self.queued = queued;
+    due.into_iter().map(|(_, note)| note).collect()
+  }
+
+  /// Time left until the next queued launch.
+  pub fn time_to_next_launch(&self, now: Instant) -> Option<Duration> {
+    self.queued.iter().map(|(launch, _)| launch.saturating_duration_since(now)).min()
+  }
+}
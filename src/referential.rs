@@ -1,12 +1,43 @@
 use std::fs;
-use crate::{GREEN_COLOR, MidiOutputEvent, RED_COLOR, WHITE_COLOR, YELLOW_COLOR};
+use std::time::Duration;
+use log::warn;
+use crate::config::HoldMode;
 use crate::launchpad::Launchpad;
+use crate::pad::Retrigger;
+use crate::playlist::Playlist;
+use crate::quantize::Quantize;
+use crate::sequence::Step;
+use crate::variation::{PickOrder, Variations};
+
+// The path column of a macro starts with it
+const MACRO_PREFIX: &str = "macro:";
+// And the one of a playlist with this one
+const PLAYLIST_PREFIX: &str = "playlist:";
 
 #[derive(Debug, Copy, Clone)]
-pub(crate) struct Note {
+pub struct Note {
   pub note_id: u8,
   pub path: &'static str,
   pub color: u8,
+  // Replaces the hold mode of the config for this note
+  pub hold_mode: Option<HoldMode>,
+  pub retrigger: Retrigger,
+  // Starting the note stops the other notes of its choke group
+  pub choke: Option<&'static str>,
+  // Fade out when stopped by the choke group, instead of stopping right away
+  pub fade_out: Option<Duration>,
+  // Files picked from on each press, the path being the first of them
+  pub variations: Option<&'static Variations>,
+  pub pick: PickOrder,
+  // Run instead of playing the path, the note is then a macro
+  pub steps: Option<&'static [Step]>,
+  // Played back to back instead of the path
+  pub playlist: Option<&'static Playlist>,
+  pub shuffle: bool,
+  // Makes it a music pad, 0 keeps it out of the crossfade of its bookmark
+  pub crossfade: Option<Duration>,
+  // Replaces the quantization of its bookmark
+  pub quantize: Option<Quantize>,
 }
 
 impl Note {
@@ -15,74 +46,88 @@ impl Note {
       note_id,
       path,
       color,
+      hold_mode: None,
+      retrigger: Retrigger::default(),
+      choke: None,
+      fade_out: None,
+      variations: None,
+      pick: PickOrder::default(),
+      steps: None,
+      playlist: None,
+      shuffle: false,
+      crossfade: None,
+      quantize: None,
     }
   }
 
-  pub fn green(note_id: u8) -> Self {
-    Note {
-      note_id,
-      path: "",
-      color: GREEN_COLOR,
+  /// Path of the sound to play next, picked among the variations if there are some.
+  pub fn next_path(&self) -> &'static str {
+    self.variations.map_or(self.path, |variations| variations.pick(self.pick))
+  }
+
+  /// Read the path column beyond a single sound: variations of the sound, a playlist or a macro.
+  fn parse_path(&mut self) -> Result<(), String> {
+    // Leaked as the path
+    if let Some(steps) = self.path.strip_prefix(MACRO_PREFIX) {
+      self.steps = Some(Vec::leak(Step::parse_all(steps)?));
+    } else if let Some(playlist) = self.path.strip_prefix(PLAYLIST_PREFIX) {
+      self.playlist = Some(Box::leak(Box::new(Playlist::load(playlist.trim())?)));
+      // Pressing the playing pad once does nothing, twice goes to the next track
+      self.retrigger = Retrigger::Ignore;
+    } else if let Some(variations) = Variations::parse(self.path)? {
+      let variations: &'static Variations = Box::leak(Box::new(variations));
+      self.path = variations.first();
+      self.variations = Some(variations);
     }
-  }
-
-  pub fn white(note_id: u8) -> Self {
-    Note {
-      note_id,
-      path: "",
-      color: WHITE_COLOR,
-    }
-  }
-
-  pub fn red(note_id: u8) -> Self {
-    Note {
-      note_id,
-      path: "",
-      color: RED_COLOR,
-    }
-  }
-
-  pub fn yellow(note_id: u8) -> Self {
-    Note {
-      note_id,
-      path: "",
-      color: YELLOW_COLOR,
+    Ok(())
+  }
+
+  /// Apply an option of the page file: a hold mode, or a `name=value` option.
+  fn parse_option(&mut self, option: &str) -> Result<(), String> {
+    match option.split_once('=') {
+      Some(("retrigger", retrigger)) => self.retrigger = retrigger.parse()?,
+      // Leaked as the path, to keep the note copyable
+      Some(("choke", group)) => self.choke = Some(Box::leak(group.trim().to_string().into_boxed_str())),
+      Some(("fade", milliseconds)) => {
+        let milliseconds = milliseconds.trim().parse().map_err(|_| format!("Invalid fade length {}", milliseconds))?;
+        self.fade_out = Some(Duration::from_millis(milliseconds));
+      }
+      Some(("pick", order)) => self.pick = order.parse()?,
+      Some(("quantize", quantize)) => self.quantize = Some(quantize.parse()?),
+      Some(("crossfade", milliseconds)) => {
+        let milliseconds = milliseconds.trim().parse().map_err(|_| format!("Invalid crossfade length {}", milliseconds))?;
+        self.crossfade = Some(Duration::from_millis(milliseconds));
+      }
+      Some((name, _)) => return Err(format!("Unknown option {}", name)),
+      None if option == "Shuffle" => self.shuffle = true,
+      None => self.hold_mode = Some(option.parse()?),
     }
+    Ok(())
   }
 
   pub fn off(note_id: u8) -> Self {
-    Note {
-      note_id,
-      path: "",
-      color: 0,
-    }
-  }
-}
-
-impl Into<MidiOutputEvent> for Note {
-  fn into(self) -> MidiOutputEvent {
-    (self.note_id, self.color, false)
-  }
-}
-
-impl Into<MidiOutputEvent> for &Note {
-  fn into(self) -> MidiOutputEvent {
-    (self.note_id, self.color, false)
+    Note::new(note_id, "", 0)
   }
 }
 
 #[derive(Debug, Clone)]
-pub(crate) struct Page {
+pub struct Page {
+  name: String,
   notes: Vec<Note>,
 }
 
 impl Page {
-  pub fn new(notes: Vec<Note>) -> Self {
+  pub fn new(name: String, notes: Vec<Note>) -> Self {
     Page {
+      name,
       notes,
     }
   }
 
+  pub fn get_name(&self) -> &str {
+    &self.name
+  }
+
   pub fn get_notes(&self) -> &Vec<Note> {
     &self.notes
   }
@@ -90,9 +135,21 @@ impl Page {
   pub fn get_note(&self, note_id: u8) -> Option<Note> {
     self.notes.iter().find(|note| note.note_id == note_id).cloned()
   }
+
+  /// The hold modes used by the notes of the page, the default one first.
+  pub fn get_hold_modes(&self, default: HoldMode) -> Vec<HoldMode> {
+    let mut modes = vec![default];
+    for note in self.notes.iter() {
+      let mode = note.hold_mode.unwrap_or(default);
+      if !modes.contains(&mode) {
+        modes.push(mode);
+      }
+    }
+    modes
+  }
 }
 
-pub(crate) struct Referential {
+pub struct Referential {
   pages: Vec<Page>,
   current_page: usize,
   current_bookmark: u8,
@@ -103,7 +160,7 @@ impl Referential {
     Referential {
       pages: vec![],
       current_page: 0,
-      current_bookmark: launchpad.bookmark_notes()[0],
+      current_bookmark: launchpad.bookmark_notes().first().copied().unwrap_or_default(),
     }
   }
 
@@ -115,7 +172,8 @@ impl Referential {
       let path = path.unwrap().path();
 
       if path.is_file() {
-        let mut page = Page::new(vec![]);
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let mut page = Page::new(name, vec![]);
 
         let content = fs::read_to_string(path.clone()).unwrap();
         // Create new for each line in content
@@ -125,7 +183,16 @@ impl Referential {
           let path = String::from(line.next().unwrap());
           let color = line.next().unwrap().parse::<u8>().unwrap();
           // We need to leak because the path can live as much as the program
-          page.notes.push(Note::new(note_id, Box::leak(path.into_boxed_str()), color));
+          let mut note = Note::new(note_id, Box::leak(path.into_boxed_str()), color);
+          if let Err(error) = note.parse_path() {
+            warn!("{} for the note {} of the page {}", error, note_id, page.name);
+          }
+          for option in line.map(str::trim).filter(|option| !option.is_empty()) {
+            if let Err(error) = note.parse_option(option) {
+              warn!("{} for the note {} of the page {}", error, note_id, page.name);
+            }
+          }
+          page.notes.push(note);
         }
         self.pages.push(page);
       }
@@ -160,12 +227,26 @@ impl Referential {
     self.current_page = self.pages.len() - 1;
   }
 
+  pub fn goto_page(&mut self, index: usize) {
+    if index < self.pages.len() {
+      self.current_page = index;
+    }
+  }
+
+  pub fn get_current_page_index(&self) -> usize {
+    self.current_page
+  }
+
   pub fn get_note(&self, note_id: u8) -> Option<Note> {
     self.pages[self.current_page].get_note(note_id)
   }
 
+  pub fn get_current_bookmark(&self) -> u8 {
+    self.current_bookmark
+  }
+
   pub fn is_current_bookmark(&self, bookmark_note_id: u8) -> bool {
-    return self.current_bookmark == bookmark_note_id;
+    self.current_bookmark == bookmark_note_id
   }
 
   pub fn set_current_bookmark(&mut self, bookmark_note_id: u8) {
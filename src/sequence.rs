@@ -0,0 +1,143 @@
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::config::HoldMode;
+
+// Separates the steps of a macro in the page file
+const STEP_SEPARATOR: char = '>';
+
+/// What a macro pad does, one step after the other.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+  /// Play a sound, its volume changed by a gain in decibels.
+  Play { path: &'static str, gain: f32 },
+  /// Wait before the next step.
+  Wait(Duration),
+  /// Go to the page at this index.
+  Page(usize),
+  /// Stop the sounds of the other pads, and the ones already played by the macro.
+  StopAll,
+  /// Switch to the hold mode, or to the next one like the hold mode button.
+  HoldMode(Option<HoldMode>),
+  /// Go to the next track of the playlist playing on this pad.
+  NextTrack(u8),
+  /// Go to the previous track of the playlist playing on this pad.
+  PreviousTrack(u8),
+}
+
+impl FromStr for Step {
+  type Err = String;
+
+  /// `play <path> [<gain>dB]`, `wait <milliseconds>`, `page <number>`, `stop`, `mode [<hold mode>]`,
+  /// `next <note>` or `previous <note>`.
+  fn from_str(step: &str) -> Result<Self, Self::Err> {
+    let step = step.trim();
+    let (name, argument) = step.split_once(' ').map_or((step, ""), |(name, argument)| (name, argument.trim()));
+    match name {
+      "play" if !argument.is_empty() => {
+        // The gain is the last word, the path may contain spaces
+        let (path, gain) = match argument.rsplit_once(' ') {
+          Some((path, gain)) if gain.ends_with("dB") => {
+            let gain = gain.trim_end_matches("dB").parse().map_err(|_| format!("Invalid gain {}", gain))?;
+            (path.trim(), gain)
+          }
+          _ => (argument, 0.0),
+        };
+        Ok(Step::Play { path: Box::leak(path.to_string().into_boxed_str()), gain })
+      }
+      "wait" => argument.parse()
+        .map(|milliseconds| Step::Wait(Duration::from_millis(milliseconds)))
+        .map_err(|_| format!("Invalid wait length {}", argument)),
+      // Numbered from 1 like on the page buttons
+      "page" => match argument.parse::<usize>() {
+        Ok(number) if number > 0 => Ok(Step::Page(number - 1)),
+        _ => Err(format!("Invalid page number {}", argument)),
+      },
+      "stop" => Ok(Step::StopAll),
+      "mode" if argument.is_empty() => Ok(Step::HoldMode(None)),
+      "mode" => Ok(Step::HoldMode(Some(argument.parse()?))),
+      "next" => argument.parse().map(Step::NextTrack).map_err(|_| format!("Invalid note {}", argument)),
+      "previous" => argument.parse().map(Step::PreviousTrack).map_err(|_| format!("Invalid note {}", argument)),
+      _ => Err(format!("Unknown step {}", step)),
+    }
+  }
+}
+
+impl Step {
+  /// Steps of a macro separated by `>`, such as `play intro.mp3 > wait 2000 > play applause.mp3 -6dB`.
+  pub fn parse_all(steps: &str) -> Result<Vec<Step>, String> {
+    steps.split(STEP_SEPARATOR).map(str::parse).collect()
+  }
+}
+
+/// Volume multiplier of a gain in decibels.
+pub fn gain_to_volume(gain: f32) -> f32 {
+  10f32.powf(gain / 20.0)
+}
+
+/// A macro being run by a pad.
+#[derive(Debug)]
+pub struct Sequence {
+  steps: &'static [Step],
+  next: usize,
+  // When the next step is due, the waits being added to it
+  due: Instant,
+  // Time left before the next step while the pad is paused
+  paused: Option<Duration>,
+}
+
+impl Sequence {
+  pub fn new(steps: &'static [Step], now: Instant) -> Self {
+    Sequence {
+      steps,
+      next: 0,
+      due: now,
+      paused: None,
+    }
+  }
+
+  /// Steps to run now, up to the next wait that isn't over.
+  pub fn due(&mut self, now: Instant) -> Vec<Step> {
+    let mut due = vec![];
+    while self.paused.is_none() && !self.is_over() && self.due <= now {
+      let step = self.steps[self.next];
+      self.next += 1;
+      match step {
+        Step::Wait(length) => self.due += length,
+        _ => due.push(step),
+      }
+    }
+    due
+  }
+
+  pub fn time_to_next_step(&self, now: Instant) -> Option<Duration> {
+    if self.paused.is_some() || self.is_over() {
+      return None;
+    }
+    Some(self.due.saturating_duration_since(now))
+  }
+
+  pub fn is_over(&self) -> bool {
+    self.next >= self.steps.len()
+  }
+
+  /// Part of the steps already run, from 0 to 1.
+  pub fn ratio(&self) -> f32 {
+    if self.steps.is_empty() {
+      return 1.0;
+    }
+    self.next as f32 / self.steps.len() as f32
+  }
+
+  pub fn pause(&mut self, now: Instant) {
+    if self.paused.is_none() {
+      self.paused = Some(self.due.saturating_duration_since(now));
+    }
+  }
+
+  pub fn resume(&mut self, now: Instant) {
+    if let Some(left) = self.paused.take() {
+      self.due = now + left;
+    }
+  }
+}
@@ -0,0 +1,117 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rand::Rng;
+
+// No file picked yet
+const NONE_PICKED: usize = usize::MAX;
+
+/// How the file to play is picked among the variations of a note.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PickOrder {
+  /// Any file but the one played last.
+  #[default]
+  Random,
+  /// Each file in turn.
+  RoundRobin,
+  /// Files with a higher weight are picked more often.
+  Weighted,
+}
+
+impl FromStr for PickOrder {
+  type Err = String;
+
+  fn from_str(order: &str) -> Result<Self, Self::Err> {
+    match order.trim() {
+      "Random" => Ok(PickOrder::Random),
+      "RoundRobin" => Ok(PickOrder::RoundRobin),
+      "Weighted" => Ok(PickOrder::Weighted),
+      _ => Err(format!("Unknown pick order {}", order)),
+    }
+  }
+}
+
+/// Files a note picks from each time it plays, to keep a repeated sound fresh.
+#[derive(Debug)]
+pub struct Variations {
+  // Path and weight of each file
+  files: Vec<(String, u32)>,
+  last: AtomicUsize,
+}
+
+impl Variations {
+  /// Variations from the files of a folder, or from a list of files separated by `|`,
+  /// each file being optionally followed by `*<weight>`. None for a single file.
+  pub fn parse(paths: &str) -> Result<Option<Variations>, String> {
+    let files: Vec<(String, u32)> = if Path::new(paths).is_dir() {
+      list_folder(paths)?.into_iter().map(|file| (file, 1)).collect()
+    } else if paths.contains('|') {
+      paths.split('|').map(parse_file).collect::<Result<_, _>>()?
+    } else {
+      return Ok(None);
+    };
+    if files.is_empty() {
+      return Err(format!("No sound found in {}", paths));
+    }
+    Ok(Some(Variations {
+      files,
+      last: AtomicUsize::new(NONE_PICKED),
+    }))
+  }
+
+  pub fn first(&self) -> &str {
+    &self.files[0].0
+  }
+
+  /// Pick the file to play next.
+  pub fn pick(&self, order: PickOrder) -> &str {
+    let count = self.files.len();
+    let last = self.last.load(Ordering::Relaxed);
+    let mut rng = rand::rng();
+    let index = match order {
+      PickOrder::RoundRobin => last.wrapping_add(1) % count,
+      // Skip the last file by picking among the others
+      PickOrder::Random if count > 1 && last < count => (last + rng.random_range(1..count)) % count,
+      PickOrder::Random => rng.random_range(0..count),
+      PickOrder::Weighted => {
+        let total: u32 = self.files.iter().map(|(_, weight)| weight).sum();
+        let mut target = rng.random_range(0..total.max(1));
+        self.files.iter()
+          .position(|(_, weight)| {
+            if target < *weight {
+              return true;
+            }
+            target -= weight;
+            false
+          })
+          .unwrap_or(0)
+      }
+    };
+    self.last.store(index, Ordering::Relaxed);
+    &self.files[index].0
+  }
+}
+
+fn parse_file(file: &str) -> Result<(String, u32), String> {
+  match file.rsplit_once('*') {
+    Some((path, weight)) => {
+      let weight = weight.trim().parse().map_err(|_| format!("Invalid weight {}", weight))?;
+      Ok((path.trim().to_string(), weight))
+    }
+    None => Ok((file.trim().to_string(), 1)),
+  }
+}
+
+/// Files of the folder sorted by name, the hidden ones being ignored.
+pub fn list_folder(folder: &str) -> Result<Vec<String>, String> {
+  let entries = fs::read_dir(folder).map_err(|error| format!("Unable to read {}: {}", folder, error))?;
+  let mut files: Vec<String> = entries
+    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+    .filter(|path| path.is_file() && !path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.')))
+    .map(|path| path.to_string_lossy().into_owned())
+    .collect();
+  files.sort();
+  Ok(files)
+}
@@ -0,0 +1,730 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use launch_soundpad::config::Config;
+use launch_soundpad::engine::Engine;
+use launch_soundpad::fake::{FakeAudioOutput, FakeMidiPort};
+use launch_soundpad::launchpad::Launchpad;
+use launch_soundpad::led::{self, LedCommand};
+use launch_soundpad::midi;
+use launch_soundpad::progress;
+use launch_soundpad::referential::Referential;
+
+// Pads of the Launchpad Mini MK3, with their own hold mode or retrigger policy
+const PAGE: &str = "81;a.mp3;5\n82;b.mp3;9\n83;music.mp3;21;Pause\n\
+  84;restart.mp3;9;retrigger=Restart\n85;stack.mp3;9;retrigger=Stack:2\n86;ignore.mp3;9;retrigger=Ignore\n87;toggle.mp3;9;retrigger=Toggle\n\
+  71;intro.mp3;45;choke=music\n72;outro.mp3;45;choke=music;fade=1000\n\
+  61;one.mp3|two.mp3|three.mp3;13;pick=RoundRobin\n62;one.mp3|two.mp3;13\n63;one.mp3*0|two.mp3*3;13;pick=Weighted\n\
+  51;macro:play a.mp3 > wait 1000 > play b.mp3 -6dB > wait 1000 > stop;13\n52;macro:page 2 > mode Stop;13\n\
+  73;calm.mp3;45;crossfade=1000\n74;party.mp3;45;crossfade=1000\n75;slow.mp3;45;crossfade=3000\n\
+  31;bar.mp3;5;quantize=Bar\n32;now.mp3;5;quantize=Off\n";
+const OTHER_PAGE: &str = "81;c.mp3;21\n";
+const PAD: u8 = 81;
+const MUSIC_PAD: u8 = 83;
+const QUANTIZED_PAD: u8 = 31;
+const UNQUANTIZED_PAD: u8 = 32;
+const STOP: u8 = 19;
+const END_SESSION: u8 = 95;
+const SWAP_HOLD_MODE: u8 = 98;
+const NEXT_PAGE: u8 = 94;
+
+struct Soundboard {
+  engine: Engine,
+  output: FakeAudioOutput,
+  virtual_output: FakeAudioOutput,
+  rx_midi: Receiver<LedCommand>,
+  // Time given to the periodic work
+  now: Instant,
+  _pages: TempFolder,
+}
+
+impl Soundboard {
+  fn new(test: &str, config: &str) -> Self {
+    Soundboard::with_pages(test, config, &[PAGE])
+  }
+
+  fn with_pages(test: &str, config: &str, pages: &[&str]) -> Self {
+    let (config, launchpad, referential, pages) = setup(test, config, pages);
+    let output = FakeAudioOutput::new();
+    let virtual_output = FakeAudioOutput::new();
+    let (tx_midi, rx_midi) = mpsc::channel();
+    let engine = Engine::new(config, launchpad, referential, Box::new(output.clone()), Box::new(virtual_output.clone()), tx_midi, None);
+    Soundboard {
+      engine,
+      output,
+      virtual_output,
+      rx_midi,
+      now: Instant::now(),
+      _pages: pages,
+    }
+  }
+
+  fn press(&mut self, note_id: u8) -> bool {
+    self.engine.handle((note_id, true))
+  }
+
+  fn release(&mut self, note_id: u8) -> bool {
+    self.engine.handle((note_id, false))
+  }
+
+  fn led_commands(&self) -> Vec<LedCommand> {
+    self.rx_midi.try_iter().collect()
+  }
+
+  // The periodic work is throttled to once per 250ms
+  fn tick(&mut self) {
+    self.now += Duration::from_millis(250);
+    self.engine.tick(self.now);
+  }
+}
+
+/// Folder of the test files, removed once the test is over.
+struct TempFolder(PathBuf);
+
+impl TempFolder {
+  fn new(name: &str) -> Self {
+    let folder = std::env::temp_dir().join(format!("launch-soundpad-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&folder).unwrap();
+    TempFolder(folder)
+  }
+
+  fn path(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl Drop for TempFolder {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.0);
+  }
+}
+
+fn setup(test: &str, config: &str, pages: &[&str]) -> (Config, Arc<Launchpad>, Referential, TempFolder) {
+  let config: Config = serde_yaml::from_str(&format!("{}\nscroll_text: false\n", config)).unwrap();
+  let launchpad = Arc::new(Launchpad::default_profile().unwrap());
+  let mut referential = Referential::new(&launchpad);
+  let folder = pages_folder(test, pages);
+  referential.init(folder.path().to_string_lossy().into_owned());
+  (config, launchpad, referential, folder)
+}
+
+/// A page with a playlist of three tracks on the pad 41, and the pads to go to its previous and next tracks.
+fn playlist_page(test: &str, options: &str) -> (TempFolder, String, Vec<String>) {
+  let folder = TempFolder::new(&format!("{}-playlist", test));
+  let m3u = folder.path().join("music.m3u");
+  fs::write(&m3u, "#EXTM3U\none.mp3\ntwo.mp3\nthree.mp3\n").unwrap();
+  let page = format!("41;playlist:{};21{}\n42;macro:previous 41;21\n43;macro:next 41;21\n", m3u.to_string_lossy(), options);
+  let tracks = ["one.mp3", "two.mp3", "three.mp3"].iter().map(|track| folder.path().join(track).to_string_lossy().into_owned()).collect();
+  (folder, page, tracks)
+}
+
+fn pages_folder(test: &str, pages: &[&str]) -> TempFolder {
+  let folder = TempFolder::new(test);
+  for (index, page) in pages.iter().enumerate() {
+    fs::write(folder.path().join(index.to_string()), page).unwrap();
+  }
+  folder
+}
+
+#[test]
+fn pressing_a_pad_plays_on_both_outputs() {
+  let mut soundboard = Soundboard::new("play", "hold_to: Normal");
+  assert!(soundboard.press(PAD));
+  assert_eq!(soundboard.output.played(), vec!["a.mp3"]);
+  assert_eq!(soundboard.virtual_output.played(), vec!["a.mp3"]);
+  assert!(soundboard.led_commands().contains(&LedCommand::pulse(PAD, 5)));
+}
+
+#[test]
+fn releasing_a_pad_keeps_playing_in_normal_mode() {
+  let mut soundboard = Soundboard::new("normal", "hold_to: Normal");
+  soundboard.press(PAD);
+  soundboard.release(PAD);
+  assert!(!soundboard.output.playback("a.mp3").unwrap().is_stopped());
+}
+
+#[test]
+fn releasing_a_pad_stops_it_in_stop_mode() {
+  let mut soundboard = Soundboard::new("stop", "hold_to: Stop");
+  soundboard.press(PAD);
+  soundboard.release(PAD);
+  assert!(soundboard.output.playback("a.mp3").unwrap().is_stopped());
+  assert!(soundboard.virtual_output.playback("a.mp3").unwrap().is_stopped());
+  assert_eq!(soundboard.led_commands().last(), Some(&LedCommand::static_color(PAD, 5)));
+}
+
+#[test]
+fn releasing_a_pad_pauses_it_in_pause_mode() {
+  use launch_soundpad::audio::Playback;
+
+  let mut soundboard = Soundboard::new("pause", "hold_to: Pause");
+  soundboard.press(PAD);
+  soundboard.release(PAD);
+  let playback = soundboard.output.playback("a.mp3").unwrap();
+  assert!(playback.is_paused());
+  // The paused pad flashes until it is resumed
+  assert_eq!(soundboard.led_commands().last(), Some(&LedCommand::flash(PAD, 5, 13)));
+
+  soundboard.press(PAD);
+  assert!(!playback.is_paused());
+  assert_eq!(soundboard.output.played().len(), 1);
+}
+
+#[test]
+fn stop_button_stops_every_sound() {
+  let mut soundboard = Soundboard::new("stop-all", "hold_to: Normal");
+  soundboard.press(PAD);
+  soundboard.press(82);
+  soundboard.press(STOP);
+  assert!(soundboard.output.playback("a.mp3").unwrap().is_stopped());
+  assert!(soundboard.output.playback("b.mp3").unwrap().is_stopped());
+}
+
+#[test]
+fn end_session_button_ends_the_session() {
+  let mut soundboard = Soundboard::new("end-session", "hold_to: Normal");
+  assert!(!soundboard.press(END_SESSION));
+}
+
+#[test]
+fn swap_hold_mode_button_changes_the_release_behavior() {
+  let mut soundboard = Soundboard::new("swap", "hold_to: Normal");
+  // Normal, then Stop
+  soundboard.press(SWAP_HOLD_MODE);
+  soundboard.press(PAD);
+  soundboard.release(PAD);
+  assert!(soundboard.output.playback("a.mp3").unwrap().is_stopped());
+}
+
+#[test]
+fn leaving_the_pause_mode_stops_the_paused_pads() {
+  let mut soundboard = Soundboard::new("leave-pause", "hold_to: Pause");
+  soundboard.press(PAD);
+  soundboard.release(PAD);
+  // Pause, then Normal
+  soundboard.press(SWAP_HOLD_MODE);
+  assert!(soundboard.output.playback("a.mp3").unwrap().is_stopped());
+
+  soundboard.press(PAD);
+  assert_eq!(soundboard.output.played().len(), 2);
+}
+
+#[test]
+fn releasing_a_pad_after_a_page_change_stops_the_sound_it_launched() {
+  let mut soundboard = Soundboard::with_pages("page-change", "hold_to: Stop", &[PAGE, OTHER_PAGE]);
+  soundboard.press(PAD);
+  let launched = soundboard.output.played()[0].clone();
+  soundboard.press(NEXT_PAGE);
+  soundboard.release(NEXT_PAGE);
+  soundboard.release(PAD);
+  assert!(soundboard.output.playback(&launched).unwrap().is_stopped());
+  assert_eq!(soundboard.output.played().len(), 1);
+}
+
+#[test]
+fn quantized_pads_wait_for_the_next_beat() {
+  let mut soundboard = Soundboard::new("quantize", "hold_to: Normal\nquantize:\n  tempo: 60\n  bookmarks: [1]");
+  soundboard.press(PAD);
+  assert!(soundboard.output.played().is_empty());
+  assert_eq!(soundboard.led_commands().last(), Some(&LedCommand::flash(PAD, 5, 0)));
+  // Pressing again while waiting doesn't queue it twice
+  soundboard.press(PAD);
+
+  soundboard.engine.update(Instant::now() + Duration::from_secs(2));
+  assert_eq!(soundboard.output.played(), vec!["a.mp3"]);
+}
+
+#[test]
+fn stop_button_cancels_the_queued_pads() {
+  let mut soundboard = Soundboard::new("quantize-stop", "hold_to: Normal\nquantize:\n  tempo: 60\n  bookmarks: [1]");
+  soundboard.press(PAD);
+  soundboard.press(STOP);
+  assert_eq!(soundboard.led_commands().last(), Some(&LedCommand::static_color(PAD, 5)));
+
+  soundboard.engine.update(Instant::now() + Duration::from_secs(2));
+  assert!(soundboard.output.played().is_empty());
+}
+
+#[test]
+fn only_the_pads_opting_in_are_quantized() {
+  let mut soundboard = Soundboard::new("quantize-opt-in", "hold_to: Normal\nquantize:\n  tempo: 60");
+  soundboard.press(PAD);
+  assert_eq!(soundboard.output.played(), vec!["a.mp3"]);
+  soundboard.press(QUANTIZED_PAD);
+  assert_eq!(soundboard.output.played(), vec!["a.mp3"]);
+  soundboard.engine.update(Instant::now() + Duration::from_secs(5));
+  assert_eq!(soundboard.output.played(), vec!["a.mp3", "bar.mp3"]);
+}
+
+#[test]
+fn pads_can_opt_out_of_a_quantized_bookmark() {
+  let mut soundboard = Soundboard::new("quantize-opt-out", "hold_to: Normal\nquantize:\n  tempo: 60\n  bookmarks: [1]");
+  soundboard.press(UNQUANTIZED_PAD);
+  assert_eq!(soundboard.output.played(), vec!["now.mp3"]);
+}
+
+#[test]
+fn pads_with_their_own_mode_ignore_the_global_one() {
+  use launch_soundpad::audio::Playback;
+
+  let mut soundboard = Soundboard::new("own-mode", "hold_to: Normal");
+  soundboard.press(MUSIC_PAD);
+  soundboard.release(MUSIC_PAD);
+  let playback = soundboard.output.playback("music.mp3").unwrap();
+  assert!(playback.is_paused());
+
+  // Normal, then Stop: the music pad is still paused
+  soundboard.press(SWAP_HOLD_MODE);
+  assert!(!playback.is_stopped());
+  soundboard.press(MUSIC_PAD);
+  assert!(!playback.is_paused());
+}
+
+#[test]
+fn stacked_copies_are_all_stopped() {
+  let mut soundboard = Soundboard::new("stack", "hold_to: Normal");
+  soundboard.press(PAD);
+  soundboard.press(PAD);
+  soundboard.press(STOP);
+  let playbacks = soundboard.output.playbacks("a.mp3");
+  assert_eq!(playbacks.len(), 2);
+  assert!(playbacks.iter().all(|playback| playback.is_stopped()));
+}
+
+#[test]
+fn stacking_stops_the_oldest_copy_beyond_the_limit() {
+  let mut soundboard = Soundboard::new("stack-limit", "hold_to: Normal");
+  for _ in 0..3 {
+    soundboard.press(85);
+  }
+  let stopped: Vec<bool> = soundboard.output.playbacks("stack.mp3").iter().map(|playback| playback.is_stopped()).collect();
+  assert_eq!(stopped, vec![true, false, false]);
+}
+
+#[test]
+fn restart_stops_the_previous_sound() {
+  let mut soundboard = Soundboard::new("restart", "hold_to: Normal");
+  soundboard.press(84);
+  soundboard.press(84);
+  let stopped: Vec<bool> = soundboard.output.playbacks("restart.mp3").iter().map(|playback| playback.is_stopped()).collect();
+  assert_eq!(stopped, vec![true, false]);
+}
+
+#[test]
+fn ignore_keeps_the_sound_playing() {
+  let mut soundboard = Soundboard::new("ignore", "hold_to: Normal");
+  soundboard.press(86);
+  soundboard.press(86);
+  assert_eq!(soundboard.output.played(), vec!["ignore.mp3"]);
+}
+
+#[test]
+fn toggle_policy_stops_the_sound() {
+  let mut soundboard = Soundboard::new("toggle-policy", "hold_to: Normal");
+  soundboard.press(87);
+  soundboard.press(87);
+  assert!(soundboard.output.playback("toggle.mp3").unwrap().is_stopped());
+  assert_eq!(soundboard.output.played().len(), 1);
+}
+
+#[test]
+fn toggle_mode_stops_on_the_next_press() {
+  let mut soundboard = Soundboard::new("toggle-mode", "hold_to: Toggle");
+  soundboard.press(PAD);
+  soundboard.release(PAD);
+  let playback = soundboard.output.playback("a.mp3").unwrap();
+  assert!(!playback.is_stopped());
+  soundboard.press(PAD);
+  assert!(playback.is_stopped());
+}
+
+#[test]
+fn starting_a_pad_stops_its_choke_group() {
+  let mut soundboard = Soundboard::new("choke", "hold_to: Normal");
+  soundboard.press(PAD);
+  soundboard.press(71);
+  soundboard.press(72);
+  assert!(soundboard.output.playback("intro.mp3").unwrap().is_stopped());
+  assert!(!soundboard.output.playback("outro.mp3").unwrap().is_stopped());
+  // Not in the group
+  assert!(!soundboard.output.playback("a.mp3").unwrap().is_stopped());
+}
+
+#[test]
+fn choked_pads_can_fade_out() {
+  let mut soundboard = Soundboard::new("choke-fade", "hold_to: Normal");
+  soundboard.press(72);
+  soundboard.press(71);
+  let playback = soundboard.output.playback("outro.mp3").unwrap();
+  assert!(!playback.is_stopped());
+
+  soundboard.engine.update(Instant::now() + Duration::from_millis(500));
+  assert!(playback.volume() > 0.3 && playback.volume() < 0.7);
+  assert!(!playback.is_stopped());
+  soundboard.engine.update(Instant::now() + Duration::from_secs(2));
+  assert!(playback.is_stopped());
+}
+
+#[test]
+fn refresh_grid_lights_the_pads_and_the_buttons() {
+  let (config, launchpad, mut referential, _pages) = setup("refresh", "hold_to: Stop", &[PAGE]);
+  let (tx_midi, rx_midi) = mpsc::channel();
+  midi::refresh_grid(&launchpad, &config, &mut referential, &tx_midi, true);
+  tx_midi.send(LedCommand::Shutdown).unwrap();
+
+  let port = FakeMidiPort::new();
+  led::forward(rx_midi, &launchpad, Some(Box::new(port.clone())), || None);
+  let sent = port.sent();
+  // Pads in their color, the hold mode button flashing between red for Stop and yellow for the music pad
+  assert!(sent.contains(&vec![144, 81, 5]));
+  assert!(sent.contains(&vec![144, 82, 9]));
+  assert!(sent.contains(&vec![144, SWAP_HOLD_MODE, 5]));
+  assert!(sent.contains(&vec![145, SWAP_HOLD_MODE, 13]));
+  assert!(sent.contains(&vec![144, STOP, 3]));
+  // Cleared before being lit
+  let clear = sent.iter().position(|message| message == &vec![144, 81, 0]).unwrap();
+  let lit = sent.iter().position(|message| message == &vec![144, 81, 5]).unwrap();
+  assert!(clear < lit);
+}
+
+#[test]
+fn round_robin_variations_play_in_turn() {
+  let mut soundboard = Soundboard::new("round-robin", "hold_to: Normal");
+  for _ in 0..4 {
+    soundboard.press(61);
+    soundboard.release(61);
+  }
+  assert_eq!(soundboard.output.played(), vec!["one.mp3", "two.mp3", "three.mp3", "one.mp3"]);
+  assert_eq!(soundboard.virtual_output.played(), soundboard.output.played());
+}
+
+#[test]
+fn random_variations_never_repeat_right_away() {
+  let mut soundboard = Soundboard::new("random", "hold_to: Normal");
+  for _ in 0..20 {
+    soundboard.press(62);
+    soundboard.release(62);
+  }
+  let played = soundboard.output.played();
+  assert!(played.windows(2).all(|pair| pair[0] != pair[1]));
+}
+
+#[test]
+fn weighted_variations_skip_the_files_without_weight() {
+  let mut soundboard = Soundboard::new("weighted", "hold_to: Normal");
+  for _ in 0..20 {
+    soundboard.press(63);
+    soundboard.release(63);
+  }
+  assert!(soundboard.output.played().iter().all(|path| path == "two.mp3"));
+}
+
+#[test]
+fn macros_run_their_steps_on_time() {
+  let mut soundboard = Soundboard::new("macro", "hold_to: Normal");
+  soundboard.press(MUSIC_PAD);
+  soundboard.press(51);
+  soundboard.release(51);
+  assert_eq!(soundboard.output.played(), vec!["music.mp3", "a.mp3"]);
+
+  soundboard.engine.update(Instant::now() + Duration::from_millis(1100));
+  assert_eq!(soundboard.output.played(), vec!["music.mp3", "a.mp3", "b.mp3"]);
+  // -6 dB is about half the volume
+  let playback = soundboard.output.playback("b.mp3").unwrap();
+  assert!((playback.play_volume() - 0.5).abs() < 0.01);
+  assert!((soundboard.virtual_output.playback("b.mp3").unwrap().play_volume() - 0.05).abs() < 0.001);
+
+  soundboard.engine.update(Instant::now() + Duration::from_millis(2100));
+  assert!(playback.is_stopped());
+  assert!(soundboard.output.playback("music.mp3").unwrap().is_stopped());
+}
+
+#[test]
+fn stopping_a_macro_skips_its_next_steps() {
+  let mut soundboard = Soundboard::new("macro-stop", "hold_to: Stop");
+  soundboard.press(51);
+  soundboard.release(51);
+  assert!(soundboard.output.playback("a.mp3").unwrap().is_stopped());
+  soundboard.engine.update(Instant::now() + Duration::from_millis(1100));
+  assert_eq!(soundboard.output.played(), vec!["a.mp3"]);
+}
+
+#[test]
+fn macros_can_change_the_page_and_the_hold_mode() {
+  let mut soundboard = Soundboard::with_pages("macro-page", "hold_to: Normal", &[PAGE, OTHER_PAGE]);
+  soundboard.press(52);
+  soundboard.release(52);
+  // The second page plays another sound, released right away in the Stop mode
+  soundboard.press(PAD);
+  soundboard.release(PAD);
+  assert_eq!(soundboard.output.played(), vec!["c.mp3"]);
+  assert!(soundboard.output.playback("c.mp3").unwrap().is_stopped());
+}
+
+#[test]
+fn macro_pads_show_how_far_they_are() {
+  let mut soundboard = Soundboard::new("macro-progress", "hold_to: Normal");
+  let launchpad = Launchpad::default_profile().unwrap();
+  soundboard.press(51);
+  // The first wait is started after the first sound, 2 of the 5 steps are done
+  let started = progress::progress_color(&launchpad, 0.4);
+  assert_eq!(soundboard.led_commands().last(), Some(&LedCommand::static_color(51, started)));
+  soundboard.engine.update(Instant::now() + Duration::from_millis(1100));
+  let almost_done = progress::progress_color(&launchpad, 0.8);
+  assert_eq!(soundboard.led_commands().last(), Some(&LedCommand::static_color(51, almost_done)));
+}
+
+#[test]
+fn playlists_play_their_tracks_back_to_back() {
+  let (_playlist, page, tracks) = playlist_page("playlist", "");
+  let mut soundboard = Soundboard::with_pages("playlist", "hold_to: Normal", &[&page]);
+  soundboard.press(41);
+  soundboard.release(41);
+  assert_eq!(soundboard.output.played(), vec![tracks[0].clone()]);
+  // The next track is on the same sinks
+  let playback = soundboard.output.playback(&tracks[0]).unwrap();
+  let virtual_playback = soundboard.virtual_output.playback(&tracks[0]).unwrap();
+  assert_eq!(playback.appended(), vec![tracks[1].clone()]);
+  assert_eq!(virtual_playback.appended(), vec![tracks[1].clone()]);
+
+  playback.end();
+  virtual_playback.end();
+  soundboard.tick();
+  assert_eq!(playback.appended(), vec![tracks[1].clone(), tracks[2].clone()]);
+  assert_eq!(soundboard.output.played().len(), 1);
+}
+
+#[test]
+fn double_press_goes_to_the_next_track() {
+  let (_playlist, page, tracks) = playlist_page("playlist-double", "");
+  let mut soundboard = Soundboard::with_pages("playlist-double", "hold_to: Normal", &[&page]);
+  soundboard.press(41);
+  soundboard.release(41);
+  // A single press is ignored
+  soundboard.press(41);
+  soundboard.release(41);
+  assert_eq!(soundboard.output.played(), vec![tracks[0].clone()]);
+  soundboard.press(41);
+  soundboard.release(41);
+  assert_eq!(soundboard.output.played(), vec![tracks[0].clone(), tracks[1].clone()]);
+  assert!(soundboard.output.playback(&tracks[0]).unwrap().is_stopped());
+  assert_eq!(soundboard.output.playback(&tracks[1]).unwrap().appended(), vec![tracks[2].clone()]);
+}
+
+#[test]
+fn double_press_goes_to_the_next_track_in_toggle_mode() {
+  let (_playlist, page, tracks) = playlist_page("playlist-toggle", "");
+  let mut soundboard = Soundboard::with_pages("playlist-toggle", "hold_to: Toggle", &[&page]);
+  soundboard.press(41);
+  soundboard.press(41);
+  soundboard.press(41);
+  soundboard.engine.update(Instant::now() + Duration::from_secs(1));
+  assert_eq!(soundboard.output.played(), vec![tracks[0].clone(), tracks[1].clone()]);
+  assert!(!soundboard.output.playback(&tracks[1]).unwrap().is_stopped());
+
+  // A single press toggles the playlist off once no second press came
+  soundboard.press(41);
+  assert!(!soundboard.output.playback(&tracks[1]).unwrap().is_stopped());
+  soundboard.engine.update(Instant::now() + Duration::from_secs(1));
+  assert!(soundboard.output.playback(&tracks[1]).unwrap().is_stopped());
+}
+
+#[test]
+fn adjacent_pads_go_to_the_next_and_previous_tracks() {
+  let (_playlist, page, tracks) = playlist_page("playlist-adjacent", "");
+  let mut soundboard = Soundboard::with_pages("playlist-adjacent", "hold_to: Normal", &[&page]);
+  soundboard.press(41);
+  soundboard.press(43);
+  soundboard.press(43);
+  soundboard.press(42);
+  assert_eq!(soundboard.output.played(), vec![tracks[0].clone(), tracks[1].clone(), tracks[2].clone(), tracks[1].clone()]);
+  // Nothing before the first track
+  soundboard.press(42);
+  soundboard.press(42);
+  assert_eq!(soundboard.output.played().len(), 5);
+}
+
+#[test]
+fn shuffled_playlists_play_every_track_once() {
+  let (_playlist, page, tracks) = playlist_page("playlist-shuffle", ";Shuffle");
+  let mut soundboard = Soundboard::with_pages("playlist-shuffle", "hold_to: Normal", &[&page]);
+  soundboard.press(41);
+  soundboard.press(43);
+  soundboard.press(43);
+  let mut played = soundboard.output.played();
+  played.sort();
+  let mut expected = tracks.clone();
+  expected.sort();
+  assert_eq!(played, expected);
+}
+
+#[test]
+fn music_pads_crossfade() {
+  let mut soundboard = Soundboard::new("crossfade", "hold_to: Normal");
+  soundboard.press(73);
+  let calm = soundboard.output.playback("calm.mp3").unwrap();
+  assert_eq!(calm.volume(), 1.0);
+  // The other sounds are left alone
+  soundboard.press(PAD);
+  soundboard.press(74);
+  let party = soundboard.output.playback("party.mp3").unwrap();
+  let virtual_party = soundboard.virtual_output.playback("party.mp3").unwrap();
+  assert_eq!(party.volume(), 0.0);
+  assert_eq!(virtual_party.volume(), 0.0);
+
+  soundboard.engine.update(Instant::now() + Duration::from_millis(500));
+  assert!(calm.volume() > 0.3 && calm.volume() < 0.7);
+  assert!(party.volume() > 0.3 && party.volume() < 0.7);
+  soundboard.engine.update(Instant::now() + Duration::from_secs(2));
+  assert!(calm.is_stopped());
+  assert!(!party.is_stopped());
+  assert_eq!(party.volume(), 1.0);
+  assert_eq!(virtual_party.volume(), 1.0);
+  assert_eq!(soundboard.output.playback("a.mp3").unwrap().volume(), 1.0);
+}
+
+#[test]
+fn music_bookmarks_crossfade_all_their_pads() {
+  let mut soundboard = Soundboard::new("crossfade-bookmark", "hold_to: Normal\ncrossfade:\n  length: 1000\n  bookmarks: [1]");
+  soundboard.press(PAD);
+  soundboard.press(82);
+  let a = soundboard.output.playback("a.mp3").unwrap();
+  assert!(!a.is_stopped());
+  assert_eq!(soundboard.output.playback("b.mp3").unwrap().volume(), 0.0);
+  soundboard.engine.update(Instant::now() + Duration::from_secs(2));
+  assert!(a.is_stopped());
+}
+
+#[test]
+fn reconnecting_the_device_reopens_the_led_port() {
+  let mut soundboard = Soundboard::new("reconnect", "hold_to: Normal");
+  soundboard.engine.device_reconnected();
+  let (tx_midi, rx_midi) = mpsc::channel();
+  for command in soundboard.led_commands() {
+    tx_midi.send(command).unwrap();
+  }
+  tx_midi.send(LedCommand::Shutdown).unwrap();
+
+  // The previous connection still accepts the messages, but they no longer reach the device
+  let previous = FakeMidiPort::new();
+  let reopened = FakeMidiPort::new();
+  let launchpad = Launchpad::default_profile().unwrap();
+  let port = reopened.clone();
+  led::forward(rx_midi, &launchpad, Some(Box::new(previous.clone())), move || Some(Box::new(port.clone())));
+  assert!(previous.sent().is_empty());
+  let sent = reopened.sent();
+  assert_eq!(sent.first().map(Vec::as_slice), Some(launchpad.programmer_mode_command()));
+  assert!(sent.contains(&vec![144, PAD, 5]));
+}
+
+#[test]
+fn unplugged_led_port_is_not_reopened_for_every_command() {
+  let (tx_midi, rx_midi) = mpsc::channel();
+  for _ in 0..20 {
+    tx_midi.send(LedCommand::static_color(PAD, 5)).unwrap();
+  }
+  tx_midi.send(LedCommand::Shutdown).unwrap();
+
+  let launchpad = Launchpad::default_profile().unwrap();
+  let reopenings = AtomicUsize::new(0);
+  led::forward(rx_midi, &launchpad, None, || {
+    reopenings.fetch_add(1, Ordering::Relaxed);
+    None
+  });
+  assert_eq!(reopenings.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn music_pads_fade_out_over_the_crossfade_of_the_starting_pad() {
+  let mut soundboard = Soundboard::new("crossfade-lengths", "hold_to: Normal");
+  soundboard.press(73);
+  soundboard.press(75);
+  let calm = soundboard.output.playback("calm.mp3").unwrap();
+  let slow = soundboard.output.playback("slow.mp3").unwrap();
+  // Halfway through the crossfade of 3 seconds, not at the end of the 1 second of the calm pad
+  soundboard.engine.update(Instant::now() + Duration::from_millis(1500));
+  assert!(!calm.is_stopped());
+  assert!(calm.volume() > 0.3 && calm.volume() < 0.7);
+  assert!((calm.volume() + slow.volume() - 1.0).abs() < 0.1);
+  soundboard.engine.update(Instant::now() + Duration::from_secs(4));
+  assert!(calm.is_stopped());
+  assert_eq!(slow.volume(), 1.0);
+}
+
+#[test]
+fn status_buttons_show_the_lost_audio_devices() {
+  let mut soundboard = Soundboard::new("bus-status", "hold_to: Normal");
+  let launchpad = Launchpad::default_profile().unwrap();
+  let status_note = launchpad.output_status_note().unwrap();
+  soundboard.output.set_available(false);
+  soundboard.tick();
+  assert!(soundboard.led_commands().contains(&LedCommand::static_color(status_note, launchpad.palette().red)));
+
+  // The sounds keep playing on the other device
+  soundboard.press(PAD);
+  assert!(soundboard.output.playback("a.mp3").unwrap().is_stopped());
+  assert!(!soundboard.virtual_output.playback("a.mp3").unwrap().is_stopped());
+
+  soundboard.output.set_available(true);
+  soundboard.tick();
+  assert!(soundboard.led_commands().contains(&LedCommand::static_color(status_note, 0)));
+}
+
+#[test]
+fn playing_pads_show_their_progress() {
+  let mut soundboard = Soundboard::new("progress", "hold_to: Normal\nplayback_progress: true");
+  let launchpad = Launchpad::default_profile().unwrap();
+  soundboard.output.set_duration(Some(Duration::from_secs(20)));
+  soundboard.press(PAD);
+  // The progress replaces the pulse
+  assert!(!soundboard.led_commands().contains(&LedCommand::pulse(PAD, 5)));
+
+  soundboard.output.playback("a.mp3").unwrap().set_position(Duration::from_secs(10));
+  soundboard.tick();
+  let halfway = progress::progress_color(&launchpad, 0.5);
+  assert!(soundboard.led_commands().contains(&LedCommand::static_color(PAD, halfway)));
+}
+
+#[test]
+fn sounds_of_a_lost_device_end_with_it() {
+  let (_playlist, page, tracks) = playlist_page("playlist-lost", "");
+  let page = format!("{}81;a.mp3;5\n", page);
+  let mut soundboard = Soundboard::with_pages("playlist-lost", "hold_to: Normal", &[&page]);
+  soundboard.press(41);
+  soundboard.press(PAD);
+  soundboard.output.set_available(false);
+  soundboard.tick();
+
+  // The playlist goes on with the other device
+  let virtual_playback = soundboard.virtual_output.playback(&tracks[0]).unwrap();
+  virtual_playback.end();
+  soundboard.tick();
+  assert_eq!(virtual_playback.appended(), vec![tracks[1].clone(), tracks[2].clone()]);
+
+  // The pad stops once its sound ended on the other device
+  soundboard.led_commands();
+  soundboard.virtual_output.playback("a.mp3").unwrap().end();
+  soundboard.tick();
+  assert!(soundboard.led_commands().contains(&LedCommand::static_color(PAD, 5)));
+}
+
+#[test]
+fn unreadable_profiles_are_skipped() {
+  let folder = pages_folder("profiles", &[]);
+  fs::write(folder.path().join(".DS_Store"), [0xff, 0xfe, 0x00]).unwrap();
+  let profiles = Launchpad::load_profiles(&folder.path().to_string_lossy());
+  assert!(profiles.iter().any(|profile| profile.name() == Launchpad::default_profile().unwrap().name()));
+}
+
+#[test]
+fn pads_opting_in_are_quantized_without_the_config() {
+  let mut soundboard = Soundboard::new("quantize-default", "hold_to: Normal");
+  soundboard.press(QUANTIZED_PAD);
+  assert!(soundboard.output.played().is_empty());
+  assert!(soundboard.led_commands().contains(&LedCommand::flash(QUANTIZED_PAD, 5, 0)));
+}